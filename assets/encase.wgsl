@@ -0,0 +1,19 @@
+// Padded struct, position is a vec3 so the struct is 32 bytes with velocity after it
+struct Particle {
+    position: vec3<f32>,
+    velocity: vec2<f32>,
+}
+
+@group(0) @binding(0) var<storage, read_write> colors: array<vec4<f32>>;
+@group(0) @binding(1) var<storage, read_write> particles: array<Particle>;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let i = invocation_id.x;
+    if i < arrayLength(&colors) {
+        colors[i] = vec4(1.0 - colors[i].rgb, colors[i].a);
+    }
+    if i < arrayLength(&particles) {
+        particles[i].position += vec3(particles[i].velocity, 0.0);
+    }
+}
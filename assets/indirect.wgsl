@@ -0,0 +1,30 @@
+#import bevy_sly_compute::indirect::{DispatchIndirectArgs, dispatch_args_for}
+
+@group(0) @binding(0) var<storage, read> values: array<u32>;
+@group(0) @binding(1) var<storage, read_write> kept: array<u32>;
+@group(0) @binding(2) var<storage, read_write> count: array<atomic<u32>>;
+@group(0) @binding(3) var<storage, read_write> args: DispatchIndirectArgs;
+
+// keep every multiple of 3, counting them as we go
+@compute @workgroup_size(64)
+fn compact(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let i = invocation_id.x;
+    if i < arrayLength(&values) && values[i] % 3u == 0u {
+        kept[atomicAdd(&count[0], 1u)] = values[i];
+    }
+}
+
+// workgroups for double, one invocation per kept value
+@compute @workgroup_size(1)
+fn write_args() {
+    args = dispatch_args_for(atomicLoad(&count[0]), 64u);
+}
+
+// dispatched with the args above, the node copies them out before this pass starts
+@compute @workgroup_size(64)
+fn double(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let i = invocation_id.x;
+    if i < atomicLoad(&count[0]) {
+        kept[i] = kept[i] * 2u;
+    }
+}
@@ -54,12 +54,15 @@ fn trigger_compute(
     simple: Res<Simple>,
 ) {
     if keys.just_pressed(KeyCode::Space) {    
-        info!("Triggering compute");            
-        compute_events.send(ComputeEvent::<Simple>::new_xyz(simple.vec.len() as u32, 1, 1));
+        let id: ComputeRequestId = compute_events.send(ComputeEvent::<Simple>::new_xyz(simple.vec.len() as u32, 1, 1)).into();
+        info!("Triggering compute {:?}", id);
     }
 }
 
-fn compute_complete( simple: Res<Simple> ) {    
+fn compute_complete( simple: Res<Simple>, mut complete_events: EventReader<ComputeComplete<Simple>> ) {    
+    for complete in complete_events.read() {
+        info!("Compute {:?} took {} frames", complete.requests, complete.frames());
+    }
     dbg!(&simple);
 }
 
@@ -0,0 +1,95 @@
+// Read back fields that aren't Pod, like Vec<Color> or structs with padding, decoded with encase
+use bevy::{prelude::*, render::{extract_resource::ExtractResource, render_graph::{RenderGraph, RenderLabel}, render_resource::{AsBindGroup, ShaderType}}, window::close_on_esc};
+use bevy_sly_compute::prelude::*;
+
+#[derive(ShaderType, Clone, Debug)]
+pub struct Particle {
+    position: Vec3, // vec3 is 16 byte aligned in wgsl, so this struct has padding
+    velocity: Vec2,
+}
+
+// plain storage, no 'staging', that needs Pod. read_staged_fields! below decodes them instead
+#[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug)]
+pub struct Simple {
+    #[storage(0, visibility(all))]
+    colors: Vec<Color>,
+
+    #[storage(1, visibility(all))]
+    particles: Vec<Particle>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SimpleLabel;
+
+impl ComputeShader for Simple {
+    fn shader() -> ShaderRef {
+        "encase.wgsl".into()
+    }
+
+    fn set_nodes(render_graph: &mut RenderGraph) {
+        render_graph.add_node(SimpleLabel, ComputeNode::<Simple>::default());
+        render_graph.add_node_edge(SimpleLabel, bevy::render::graph::CameraDriverLabel);
+    }
+
+    read_staged_fields! {
+        0 => colors,
+        1 => particles,
+    }
+}
+
+fn main() {
+    App::new()
+        .add_plugins((
+            DefaultPlugins,
+            ComputePlugin::<Simple>::default(),
+        ))
+        .insert_resource(Simple {
+            colors: vec![Color::rgba_linear(1.0, 0.0, 0.0, 1.0), Color::rgba_linear(0.0, 0.5, 1.0, 0.5)],
+            particles: vec![
+                Particle { position: Vec3::ZERO, velocity: Vec2::new(1.0, 2.0) },
+                Particle { position: Vec3::new(1.0, 1.0, 1.0), velocity: Vec2::new(-1.0, 0.5) },
+            ],
+        })
+        .add_systems(Startup, setup)
+        .add_systems(Update, (trigger_compute, close_on_esc))
+        .add_systems(Last, compute_complete.run_if(on_event::<ComputeComplete<Simple>>()))
+        .run();
+}
+
+fn trigger_compute(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut compute_events: EventWriter<ComputeEvent<Simple>>,
+    simple: Res<Simple>,
+) {
+    if keys.just_pressed(KeyCode::Space) {
+        // one invocation per element
+        let count = simple.colors.len().max(simple.particles.len()) as u32;
+        compute_events.send(ComputeEvent::<Simple>::for_invocations(UVec3::new(count, 1, 1)));
+    }
+}
+
+// colors are inverted and particles moved by their velocity each time
+fn compute_complete(simple: Res<Simple>) {
+    info!("colors: {:?}", simple.colors);
+    info!("particles: {:?}", simple.particles);
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
+
+    info!("Press SPACE to run the compute shader");
+    commands.spawn(Text2dBundle {
+        text: Text {
+            sections: vec![TextSection {
+                value: "Press SPACE to run the compute shader\nCheck console".to_string(),
+                style: TextStyle {
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
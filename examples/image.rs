@@ -11,7 +11,6 @@ use bevy_inspector_egui::{
 };
 
 const TEXTURE_SIZE: u32 = 1024;
-const WORKGROUP_SIZE: u32 = 8; // should match shader
 
 fn main() {
     App::new()
@@ -24,6 +23,7 @@ fn main() {
     .init_resource::<Simple>()
     .register_type::<Simple>()
     //run startup and trigger compute shader on start
+    // the pipeline is still compiling at Startup, the event is held until it's ready
     .add_systems(Startup, (setup, trigger_computue).chain()) 
 
     // run compute when resource changes or compute shader is modified
@@ -101,26 +101,22 @@ impl ComputeShader for Simple {
 // helper to trigger compute passes of the correct size
 fn trigger_computue(    
     mut compute: EventWriter<ComputeEvent<Simple>>,
+    simple: Res<Simple>,
 ) {
-    // Size of the dispatch, here we are computing the entire texture
-    let dispatch_size = UVec3 { 
-        // dispatch size
-        x: TEXTURE_SIZE / WORKGROUP_SIZE,
-        y: TEXTURE_SIZE / WORKGROUP_SIZE,
-        z: 1,
-    };
     // You can define the many passes and entry points if you want
     compute.send(ComputeEvent::<Simple> {
         passes: vec![
             Pass {
-                entry: "main", // entry point to the shader
-                workgroups: vec![dispatch_size],
+                iterations: 1, // how many times to dispatch the workgroups
+                // entry point to the shader, and one invocation per texel so we compute the
+                // entire texture, the workgroups come from the shader's @workgroup_size
+                ..Pass::for_image("main", &simple.image)
             },
         ],
         ..default()
     });
     // There are a few helper functions to make this more concise if you dont need all the options
-    //compute.send(ComputeEvent::<Simple>::new(dispatch_size));    
+    //compute.send(ComputeEvent::<Simple>::for_image(&simple.image));    
 }
 
 // Do something when compute is complete, where we will save the image
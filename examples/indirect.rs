@@ -0,0 +1,109 @@
+// Compact values with one pass, then run a second pass over only the values it kept.
+// The first pass counts what it keeps, write_args turns the count into workgroups, and the
+// indirect pass dispatches that many, so the cpu never has to read the count back first
+use bevy::{prelude::*, render::{extract_resource::ExtractResource, render_graph::{RenderGraph, RenderLabel}, render_resource::{AsBindGroup, Buffer}, renderer::RenderDevice}};
+use bevy_sly_compute::prelude::*;
+
+const VALUES: u32 = 1000;
+
+#[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug)]
+pub struct Compaction {
+    #[storage(0, visibility(all), read_only)]
+    values: Vec<u32>,
+
+    #[storage(1, visibility(all), staging)]
+    kept: Vec<u32>,
+
+    // atomic in wgsl, a single counter
+    #[storage(2, visibility(all), staging)]
+    count: Vec<u32>,
+
+    // made with indirect_args_buffer, write_args fills it and double dispatches from it
+    #[storage(3, visibility(all), buffer)]
+    args: Buffer,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct CompactionLabel;
+
+impl ComputeShader for Compaction {
+    fn shader() -> ShaderRef {
+        "indirect.wgsl".into()
+    }
+
+    fn set_nodes(render_graph: &mut RenderGraph) {
+        render_graph.add_node(CompactionLabel, ComputeNode::<Compaction>::default());
+        render_graph.add_node_edge(CompactionLabel, bevy::render::graph::CameraDriverLabel);
+    }
+
+    fn entry_points<'a>() -> Vec<&'a str> {
+        vec!["compact", "write_args", "double"]
+    }
+}
+
+fn main() {
+    App::new()
+        .add_plugins((DefaultPlugins, ComputePlugin::<Compaction>::default()))
+        .add_systems(Startup, setup)
+        .add_systems(Update, dispatch.run_if(compute_ready::<Compaction>()))
+        .add_systems(Update, check_complete)
+        .run();
+}
+
+fn setup(mut commands: Commands, render_device: Res<RenderDevice>) {
+    commands.spawn(Camera2dBundle::default());
+
+    commands.insert_resource(Compaction {
+        values: (0..VALUES).collect(),
+        kept: vec![0; VALUES as usize],
+        count: vec![0],
+        args: indirect_args_buffer(
+            &render_device,
+            Some("compaction_args"),
+            &[DispatchIndirectArgs::default()],
+        ),
+    });
+}
+
+fn dispatch(mut compute_events: EventWriter<ComputeEvent<Compaction>>, mut sent: Local<bool>) {
+    if *sent {
+        return;
+    }
+    // one invocation per value
+    let mut event = ComputeEvent::<Compaction>::for_invocations(UVec3::new(VALUES, 1, 1));
+    event
+        .add_pass("write_args", UVec3::ONE)
+        .add_indirect_pass("double", 3, 0);
+    compute_events.send(event);
+    *sent = true;
+}
+
+fn check_complete(
+    compaction: Option<Res<Compaction>>,
+    mut complete_events: EventReader<ComputeComplete<Compaction>>,
+    mut failed_events: EventReader<ComputeFailed<Compaction>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    for failed in failed_events.read() {
+        panic!("{:?} failed: {}", failed.requests, failed.error);
+    }
+    if complete_events.read().count() == 0 {
+        return;
+    }
+    let Some(compaction) = compaction else {
+        return;
+    };
+
+    // compact keeps every multiple of 3, in whatever order the invocations ran
+    let count = compaction.count[0] as usize;
+    let mut kept = compaction.kept[..count].to_vec();
+    kept.sort();
+    let expected = (0..VALUES)
+        .filter(|value| value % 3 == 0)
+        .map(|value| value * 2)
+        .collect::<Vec<_>>();
+    assert_eq!(kept, expected, "only the kept values are doubled");
+    assert!(compaction.kept[count..].iter().all(|value| *value == 0));
+    info!("kept and doubled {} of {} values", count, VALUES);
+    exit.send(AppExit);
+}
@@ -0,0 +1,101 @@
+// Dispatch every frame and check each dispatch completes exactly once, in order.
+// DefaultPlugins includes PipelinedRenderingPlugin, so results can arrive more than one per frame.
+// Dispatches sent before the pipeline is ready are held until it is, so none of them should fail
+use bevy::{prelude::*, render::{extract_resource::ExtractResource, render_graph::{RenderGraph, RenderLabel}, render_resource::AsBindGroup}};
+use bevy_sly_compute::prelude::*;
+
+const DISPATCHES: usize = 300;
+
+#[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug)]
+pub struct Simple {
+    #[uniform(0)]
+    uni: f32,
+
+    #[storage(1, visibility(all), staging)]
+    vec: Vec<f32>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SimpleLabel;
+
+impl ComputeShader for Simple {
+    fn shader() -> ShaderRef {
+        "basic.wgsl".into()
+    }
+
+    fn set_nodes(render_graph: &mut RenderGraph) {
+        render_graph.add_node(SimpleLabel, ComputeNode::<Simple>::default());
+        render_graph.add_node_edge(SimpleLabel, bevy::render::graph::CameraDriverLabel);
+    }
+}
+
+#[derive(Resource, Default)]
+struct Tracker {
+    sent: Vec<ComputeRequestId>,
+    completed: Vec<ComputeRequestId>,
+    failed: Vec<ComputeRequestId>,
+    most_in_one_frame: usize,
+}
+
+fn main() {
+    App::new()
+        .add_plugins((DefaultPlugins, ComputePlugin::<Simple>::default()))
+        .insert_resource(Simple {
+            uni: 1.0,
+            vec: vec![0.0; 4],
+        })
+        .init_resource::<Tracker>()
+        .add_systems(Startup, setup)
+        .add_systems(Update, dispatch)
+        .add_systems(Update, check_complete)
+        .run();
+}
+
+fn setup(mut commands: Commands) {
+    commands.spawn(Camera2dBundle::default());
+}
+
+fn dispatch(mut compute: ComputeCommands<Simple>, mut tracker: ResMut<Tracker>) {
+    if tracker.sent.len() < DISPATCHES {
+        let id = compute.send(ComputeEvent::<Simple>::new_xyz(4, 1, 1));
+        tracker.sent.push(id);
+    }
+}
+
+fn check_complete(
+    mut tracker: ResMut<Tracker>,
+    mut complete_events: EventReader<ComputeComplete<Simple>>,
+    mut failed_events: EventReader<ComputeFailed<Simple>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let mut count = 0;
+    for complete in complete_events.read() {
+        count += 1;
+        for request in complete.requests.iter() {
+            if tracker.completed.contains(request) {
+                error!("{:?} completed more than once", request);
+            }
+            if tracker.completed.last().is_some_and(|last| last > request) {
+                error!("{:?} completed out of order", request);
+            }
+            tracker.completed.push(*request);
+        }
+    }
+    tracker.most_in_one_frame = tracker.most_in_one_frame.max(count);
+
+    // still counted so the check below finishes, and reports them
+    for failed in failed_events.read() {
+        error!("{:?} failed: {}", failed.requests, failed.error);
+        tracker.failed.extend(failed.requests.iter());
+    }
+
+    if tracker.completed.len() + tracker.failed.len() == DISPATCHES {
+        assert!(tracker.failed.is_empty(), "{} dispatches failed", tracker.failed.len());
+        assert_eq!(tracker.completed, tracker.sent, "every dispatch completes exactly once, in order");
+        info!(
+            "{} dispatches completed in order, up to {} in one frame",
+            DISPATCHES, tracker.most_in_one_frame
+        );
+        exit.send(AppExit);
+    }
+}
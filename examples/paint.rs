@@ -22,9 +22,6 @@ use bevy_xpbd_3d::{
 use common_helper::{cursor::CursorPlugin, *};
 use paint_resources::brush::{self, Brush};
 
-// Size of workgroup, shader should match,
-// Note: I have done almost no testing on workgroup size and is an entire topic on its own
-const WORKGROUP_SIZE: u32 = 8; // @workgroup_size(8, 8, 1)
                                
 fn main() {
     App::new()
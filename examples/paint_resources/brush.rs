@@ -11,7 +11,7 @@ use bevy_inspector_egui::{
     bevy_egui::EguiContexts, inspector_options::{std_options::NumberDisplay, ReflectInspectorOptions}, InspectorOptions
 };
 use bevy_sly_compute::prelude::*;
-use crate::{common_helper::cursor::CursorEvent, WORKGROUP_SIZE};
+use crate::common_helper::cursor::CursorEvent;
 
 
 // Our brush to paint any thing with standard material
@@ -78,7 +78,6 @@ pub fn brush_active(
     mut gizmos: Gizmos,
     filter_query: Query<(&Handle<StandardMaterial>, &Transform)>, 
     standard_materials: Res<Assets<StandardMaterial>>,   
-    images: Res<Assets<Image>>,   
     mut contexts: EguiContexts,
 ) {
     let ctx = contexts.ctx_mut();
@@ -121,9 +120,8 @@ pub fn brush_active(
         if button_input.pressed(MouseButton::Left) {
             gizmos.sphere(event.pos, Quat::IDENTITY, world_radius, Color::RED);  
 
-            // we need image size for dispatch size
-            let image = images.get(handle).unwrap();            
-            compute_event.send(ComputeEvent::<Brush>::new_xyz(image.width() / WORKGROUP_SIZE, image.height() / WORKGROUP_SIZE, 1));
+            // dispatch size comes from the image and the shader's @workgroup_size
+            compute_event.send(ComputeEvent::<Brush>::for_image(handle));
         } else {
             gizmos.sphere(event.pos, Quat::IDENTITY, world_radius, Color::LIME_GREEN);
         }
@@ -0,0 +1,83 @@
+// Await the results of a compute shader from an async task, instead of listening for ComputeComplete
+use bevy::{prelude::*, render::{extract_resource::ExtractResource, render_graph::{RenderGraph, RenderLabel}, render_resource::AsBindGroup}, tasks::AsyncComputeTaskPool, window::close_on_esc};
+use bevy_sly_compute::prelude::*;
+
+#[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug)]
+pub struct Simple {
+    #[uniform(0)]
+    uni: f32,
+
+    #[storage(1, visibility(all), staging)]
+    vec: Vec<f32>,
+}
+
+#[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+pub struct SimpleLabel;
+
+impl ComputeShader for Simple {
+    fn shader() -> ShaderRef {
+        "basic.wgsl".into()
+    }
+
+    fn set_nodes(render_graph: &mut RenderGraph) {
+        render_graph.add_node(SimpleLabel, ComputeNode::<Simple>::default());
+        render_graph.add_node_edge(SimpleLabel, bevy::render::graph::CameraDriverLabel);
+    }
+}
+
+fn main() {
+    App::new()
+        .add_plugins((
+            DefaultPlugins,
+            ComputePlugin::<Simple>::default(),
+        ))
+        .insert_resource(Simple {
+            uni: 1.0,
+            vec: vec![1.0, 2.0, 3.0, 4.0],
+        })
+        .add_systems(Startup, setup)
+        .add_systems(Update, (trigger_compute, close_on_esc))
+        .run();
+}
+
+fn trigger_compute(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut compute: ComputeCommands<Simple>,
+    simple: Res<Simple>,
+) {
+    if keys.just_pressed(KeyCode::Space) {
+        // returns a future that resolves once the results are back in the app world
+        let task = compute.dispatch(ComputeEvent::<Simple>::new_xyz(simple.vec.len() as u32, 1, 1));
+        info!("Triggering compute {:?}", task.request());
+
+        AsyncComputeTaskPool::get()
+            .spawn(async move {
+                match task.await {
+                    Some(output) => info!("Compute {:?} complete: {:?}", output.request, output.data),
+                    None => warn!("Compute dropped"),
+                }
+            })
+            .detach();
+    }
+}
+
+fn setup(mut commands: Commands) {
+
+    commands.spawn(Camera2dBundle::default());
+
+    info!("Press SPACE to run the compute shader");
+    commands.spawn(Text2dBundle {
+        text: Text {
+            sections: vec![TextSection {
+                value: "Press SPACE to run the compute shader\nCheck console".to_string(),
+                style: TextStyle {
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            }],
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+}
@@ -27,18 +27,6 @@ use crate::common_helper::MainCamera;
 // Sizes of the textures we will be using
 const TEXTURE_SIZE: usize = 1024;
 
-// Size of workgroup, shader should match,
-const WORKGROUP_SIZE: u32 = 8; // @workgroup_size(8, 8, 1)
-                               // Note: I have done almost no testing on workgroup size and is an entire topic on its own
-
-// In this example our dispatch size let us cover the entire texture,
-// it completely configurable on per pass per dispatch basis
-const DISPATCH_SIZE: UVec3 = UVec3 {
-    x: TEXTURE_SIZE as u32 / WORKGROUP_SIZE,
-    y: TEXTURE_SIZE as u32 / WORKGROUP_SIZE,
-    z: 1,
-};
-
 // Marker for our ground
 #[derive(Component)]
 pub struct Ground;
@@ -156,8 +144,9 @@ fn setup(
         image: image_handle.clone(),
         ..default()
     });
-    // we also want it to run once at startup
-    compute_terrain.send(ComputeEvent::<HeightGen>::new(DISPATCH_SIZE));
+    // we also want it to run once at startup, covering the entire texture,
+    // the workgroups come from the shader's @workgroup_size
+    compute_terrain.send(ComputeEvent::<HeightGen>::for_image(&image_handle));
 
     commands.insert_resource(HeightBrush {
         image: image_handle.clone(),
@@ -246,8 +235,11 @@ fn image_updated(
 }
 
 // dispatch compute pass
-fn height_compute(mut compute_terrain: EventWriter<ComputeEvent<HeightGen>>) {
-    compute_terrain.send(ComputeEvent::<HeightGen>::new(DISPATCH_SIZE));
+fn height_compute(
+    mut compute_terrain: EventWriter<ComputeEvent<HeightGen>>,
+    height_gen: Res<HeightGen>,
+) {
+    compute_terrain.send(ComputeEvent::<HeightGen>::for_image(&height_gen.image));
 }
 
 // UI to see everything
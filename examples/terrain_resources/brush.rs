@@ -1,7 +1,7 @@
 use std::fmt::Debug;
 
 use bevy::{
-    input::mouse::MouseWheel, prelude::*, render::{
+    input::mouse::MouseWheel, math::URect, prelude::*, render::{
         extract_resource::ExtractResource,
         render_graph::{RenderGraph, RenderLabel},
         render_resource::AsBindGroup,
@@ -11,7 +11,7 @@ use bevy_inspector_egui::{
     bevy_egui::EguiContexts, inspector_options::{std_options::NumberDisplay, ReflectInspectorOptions}, InspectorOptions
 };
 use bevy_sly_compute::prelude::*;
-use crate::{common_helper::cursor::CursorEvent, DISPATCH_SIZE};
+use crate::{common_helper::cursor::CursorEvent, TEXTURE_SIZE};
 
 use super::{height_gen::HeightGenLabel, mesh_config::TerrainMeshConfig};
 
@@ -81,7 +81,19 @@ pub fn brush_active<T: Component>(
     mut gizmos: Gizmos,
     filter_query: Query<Entity, With<T>>,    
     mut contexts: EguiContexts,
+    mut stroke: Local<Option<URect>>,
 ) {    
+    // Read back the image once the stroke is done, this updates the terrain mesh and collider
+    // only the part of the image the stroke touched is copied
+    if button_input.just_released(MouseButton::Left) {
+        // no passes, painting again here would leave an extra dab where the button was released
+        let mut event = ComputeEvent::<HeightBrush>::no_passes();
+        if let Some(stroke) = stroke.take() {
+            event = event.readback_region(&brush.image, stroke);
+        }
+        compute_event.send(event);
+    }
+
     // Dont respond to cursor events if we are over an egui area
     // Note: had issues where this only worked like half the time
     let ctx = contexts.ctx_mut();
@@ -111,7 +123,18 @@ pub fn brush_active<T: Component>(
                     
         if button_input.pressed(MouseButton::Left) {
             gizmos.sphere(event.pos, Quat::IDENTITY, radius, Color::RED);  
-            compute_event.send(ComputeEvent::<HeightBrush>::new(DISPATCH_SIZE));
+            // paint on the gpu only while the button is held, no need to copy the image back every frame
+            compute_event.send(ComputeEvent::<HeightBrush>::for_image(&brush.image).no_staging());
+
+            // track the area of the image the stroke has painted, in pixels
+            let size = TEXTURE_SIZE as f32;
+            let center = Vec2::new(brush.position.x, 1.0 - brush.position.y) * size;
+            let half_size = Vec2::splat(brush.radius * size + 1.0);
+            let dab = URect::from_corners(
+                (center - half_size).max(Vec2::ZERO).as_uvec2(),
+                (center + half_size).min(Vec2::splat(size)).ceil().as_uvec2(),
+            );
+            *stroke = Some(stroke.map_or(dab, |stroke| stroke.union(dab)));
         } else {
             gizmos.sphere(event.pos, Quat::IDENTITY, radius, Color::LIME_GREEN);
         }
@@ -1,11 +1,81 @@
-use bevy::prelude::*;
+use bevy::{
+    math::URect,
+    prelude::*,
+    render::render_resource::{Buffer, TextureDimension},
+};
 use crossbeam_channel::{Receiver, Sender};
-use crate::ComputeTrait;
+use std::marker::PhantomData;
+
+use crate::{
+    BufferDimensions, ComputeError, ComputeFailed, ComputeRequestId, ComputeTrait, Pass,
+    StagingBufferPool,
+};
 
 /// Data to pass from Render World to App World
 pub struct ComputeMessage<T: ComputeTrait> {
-    pub data: Option<T>,
-    pub images: Vec<(Handle<Image>, Vec<u8>)>,
+    pub requests: Vec<ComputeRequestId>,
+    pub submitted_frame: u32,
+    pub passes: Vec<Pass>,
+    pub dont_copy: bool,
+    /// staging buffers for storage bindings, still mapped
+    pub storage: Vec<(u32, Buffer)>,
+    /// unpadded image data, per mip level copied
+    pub images: Vec<(Handle<Image>, Vec<ImageData>)>,
+    pub _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> ComputeMessage<T> {
+    /// Return the staging buffers to the pool without reading them
+    pub fn recycle(self, pool: &StagingBufferPool<T>) {
+        for (_, buffer) in self.storage {
+            buffer.unmap();
+            pool.release(buffer);
+        }
+    }
+
+    /// Fold an older message into this one, newer storage replaces older for the same binding,
+    /// images are kept in order so newer writes land last
+    pub fn coalesce(&mut self, older: Self, pool: &StagingBufferPool<T>) {
+        let mut requests = older.requests;
+        requests.append(&mut self.requests);
+        self.requests = requests;
+
+        let mut passes = older.passes;
+        passes.append(&mut self.passes);
+        self.passes = passes;
+
+        self.submitted_frame = older.submitted_frame;
+        self.dont_copy &= older.dont_copy;
+
+        for (index, buffer) in older.storage {
+            if self.storage.iter().any(|(i, _)| *i == index) {
+                buffer.unmap();
+                pool.release(buffer);
+            } else {
+                self.storage.push((index, buffer));
+            }
+        }
+
+        let mut images = older.images;
+        images.append(&mut self.images);
+        self.images = images;
+    }
+}
+
+/// What the render world does with a result when the app world hasn't caught up and the
+/// channel is full
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ComputeChannelPolicy {
+    /// Drop the oldest result waiting in the channel to make room
+    DropOldest,
+    /// Drop the result that doesn't fit
+    DropNewest,
+    /// Merge every waiting result into the newest one, every request still completes
+    /// but they share one [`ComputeComplete`](crate::ComputeComplete)
+    Coalesce,
+    /// Never drop anything, results queue up until the app world reads them
+    #[default]
+    Unbounded,
 }
 
 /// Channel resource used to receive ComputeMessage from render world.
@@ -15,12 +85,235 @@ pub struct ComputeReceiver<T: ComputeTrait> (pub Receiver<ComputeMessage<T>>);
 
 /// Channel resource used to send time from the render world.
 #[derive(Resource, Deref, DerefMut)]
-pub struct ComputeSender<T: ComputeTrait> ( pub Sender<ComputeMessage<T>>);
+pub struct ComputeSender<T: ComputeTrait> {
+    #[deref]
+    pub sender: Sender<ComputeMessage<T>>,
+    /// lets the render world take back results waiting in the channel, for
+    /// [`ComputeChannelPolicy::DropOldest`] and [`ComputeChannelPolicy::Coalesce`]
+    pub(crate) waiting: Option<Receiver<ComputeMessage<T>>>,
+}
+
+/// Channel resource used to send errors from the render world, unbounded so none are lost
+#[derive(Resource, Deref)]
+pub struct ComputeErrorSender<T: ComputeTrait>(pub Sender<ComputeFailed<T>>);
+
+impl<T: ComputeTrait> ComputeErrorSender<T> {
+    /// Log the error and send it to the app world as [`ComputeFailed`]
+    pub fn report(&self, requests: Vec<ComputeRequestId>, error: ComputeError) {
+        error!("{} compute error for {:?}: {}", std::any::type_name::<T>(), requests, error);
+        // if the app world is gone there is no one left to tell
+        let _ = self.0.try_send(ComputeFailed::new(requests, error));
+    }
+
+    /// Send the error to the app world, only logged at debug, for requests the settings asked
+    /// to drop like [`ComputeError::Dropped`] and [`ComputeError::Superseded`]
+    pub fn notify(&self, requests: Vec<ComputeRequestId>, error: ComputeError) {
+        debug!("{} compute {:?}: {}", std::any::type_name::<T>(), requests, error);
+        let _ = self.0.try_send(ComputeFailed::new(requests, error));
+    }
+}
+
+/// Channel resource used to receive errors in the app world
+#[derive(Resource, Deref)]
+pub struct ComputeErrorReceiver<T: ComputeTrait>(pub Receiver<ComputeFailed<T>>);
+
+pub fn create_error_channels<T: ComputeTrait>() -> (ComputeErrorSender<T>, ComputeErrorReceiver<T>) {
+    let (s, r) = crossbeam_channel::unbounded::<ComputeFailed<T>>();
+    (ComputeErrorSender(s), ComputeErrorReceiver(r))
+}
 
 /// Creates channels used for sending time between the render world and the main world.
-pub fn create_compute_channels<'a, T: ComputeTrait>() -> (ComputeSender<T>, ComputeReceiver<T>) {
-    // bound the channel to 2 since when pipelined the render phase can finish before
-    // the time system runs.
-    let (s, r) = crossbeam_channel::bounded::<ComputeMessage<T>>(2);
-    (ComputeSender(s), ComputeReceiver(r))
-}
\ No newline at end of file
+pub fn create_compute_channels<'a, T: ComputeTrait>(
+    frames_in_flight: usize,
+    policy: ComputeChannelPolicy,
+) -> (ComputeSender<T>, ComputeReceiver<T>) {
+    let (s, r) = match policy {
+        ComputeChannelPolicy::Unbounded => crossbeam_channel::unbounded::<ComputeMessage<T>>(),
+        // one slot per dispatch in flight, plus one since when pipelined the render phase
+        // can finish before listen_receiver runs.
+        _ => crossbeam_channel::bounded::<ComputeMessage<T>>(frames_in_flight.max(1) + 1),
+    };
+    let waiting = matches!(
+        policy,
+        ComputeChannelPolicy::DropOldest | ComputeChannelPolicy::Coalesce
+    )
+    .then(|| r.clone());
+    (ComputeSender { sender: s, waiting }, ComputeReceiver(r))
+}
+
+/// Unpadded texels copied back from one mip level of an image
+pub struct ImageData {
+    pub mip_level: u32,
+    /// part of the mip level that was copied, None if all of it
+    pub region: Option<URect>,
+    pub data: Vec<u8>,
+}
+
+impl ImageData {
+    /// Write the texels into the image, following the layout bevy uploads `Image::data` with,
+    /// every mip level of a layer before the next layer. Compressed formats are in whole blocks
+    pub fn write_to(self, image: &mut Image) {
+        let descriptor = &image.texture_descriptor;
+        let format = descriptor.format;
+        let is_3d = descriptor.dimension == TextureDimension::D3;
+        let layers = if is_3d {
+            1
+        } else {
+            descriptor.size.depth_or_array_layers as usize
+        };
+
+        // layout of a mip level in one layer, 3d textures include every slice
+        let mip_dim = |mip_level: u32| {
+            let size = descriptor.size.mip_level_size(mip_level, descriptor.dimension);
+            let depth = if is_3d { size.depth_or_array_layers } else { 1 };
+            BufferDimensions::for_format(
+                size.width as usize,
+                size.height as usize,
+                depth as usize,
+                format,
+            )
+        };
+        let mip_bytes =
+            |mip_level: u32| mip_dim(mip_level).map_or(0, |dim| dim.unpadded_bytes_per_row * dim.rows());
+        let mip_offset = (0..self.mip_level).map(mip_bytes).sum::<usize>();
+        let layer_bytes = (0..descriptor.mip_level_count).map(mip_bytes).sum::<usize>();
+
+        // whole image in one copy, just take the data
+        if self.region.is_none()
+            && layers == 1
+            && descriptor.mip_level_count == 1
+            && self.data.len() == image.data.len()
+        {
+            image.data = self.data;
+            return;
+        }
+
+        let (Some(dim), Some(block_size)) = (mip_dim(self.mip_level), format.block_copy_size(None))
+        else {
+            return;
+        };
+        let (block_width, block_height) = format.block_dimensions();
+        let region = self
+            .region
+            .unwrap_or_else(|| URect::new(0, 0, dim.width as u32, dim.height as u32));
+        let Some(region_dim) = BufferDimensions::for_format(
+            region.width() as usize,
+            region.height() as usize,
+            1,
+            format,
+        ) else {
+            return;
+        };
+        if region_dim.unpadded_bytes_per_row == 0 || region_dim.rows_per_image == 0 {
+            return;
+        }
+        let x_offset = (region.min.x / block_width) as usize * block_size as usize;
+        let first_row = (region.min.y / block_height) as usize;
+
+        for (row, row_data) in self
+            .data
+            .chunks_exact(region_dim.unpadded_bytes_per_row)
+            .enumerate()
+        {
+            // slice for 3d textures, layer for array textures
+            let z = row / region_dim.rows_per_image;
+            let y = first_row + row % region_dim.rows_per_image;
+            let slice_start = if is_3d {
+                mip_offset + z * dim.rows_per_image * dim.unpadded_bytes_per_row
+            } else {
+                z * layer_bytes + mip_offset
+            };
+            let start = slice_start + y * dim.unpadded_bytes_per_row + x_offset;
+            let Some(texels) = image.data.get_mut(start..start + row_data.len()) else {
+                warn!("image data smaller than its texture descriptor, skipping readback");
+                return;
+            };
+            texels.copy_from_slice(row_data);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::{
+        render_asset::RenderAssetUsages,
+        render_resource::{Extent3d, TextureFormat},
+    };
+
+    use super::*;
+    use crate::tests::{message, Simple};
+
+    fn image(width: u32, height: u32, layers: u32) -> Image {
+        Image::new_fill(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: layers,
+            },
+            TextureDimension::D2,
+            &[0, 0, 0, 0],
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::default(),
+        )
+    }
+
+    #[test]
+    fn writes_a_region() {
+        let mut image = image(4, 4, 1);
+        ImageData {
+            mip_level: 0,
+            region: Some(URect::new(1, 1, 3, 3)),
+            data: vec![255; 2 * 2 * 4],
+        }
+        .write_to(&mut image);
+
+        for y in 0..4 {
+            for x in 0..4 {
+                let inside = (1..3).contains(&x) && (1..3).contains(&y);
+                let texel = &image.data[(y * 4 + x) * 4..][..4];
+                assert_eq!(texel, [if inside { 255 } else { 0 }; 4], "texel {}, {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn writes_a_mip_level_of_every_layer() {
+        // each layer has its 4x4 mip then its 2x2 mip, 64 + 16 bytes
+        let mut image = image(4, 4, 2);
+        image.texture_descriptor.mip_level_count = 2;
+        image.data = vec![0; 2 * 80];
+
+        let mut data = vec![1; 16];
+        data.extend([2; 16]);
+        ImageData {
+            mip_level: 1,
+            region: None,
+            data,
+        }
+        .write_to(&mut image);
+
+        assert!(image.data[..64].iter().all(|&b| b == 0));
+        assert!(image.data[64..80].iter().all(|&b| b == 1));
+        assert!(image.data[80..144].iter().all(|&b| b == 0));
+        assert!(image.data[144..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn coalesce_puts_older_results_first() {
+        let pool = StagingBufferPool::<Simple>::new(1);
+        let mut newest = message(2);
+        newest.dont_copy = false;
+
+        newest.coalesce(message(1), &pool);
+        newest.coalesce(message(0), &pool);
+
+        assert_eq!(
+            newest.requests,
+            vec![ComputeRequestId(0), ComputeRequestId(1), ComputeRequestId(2)]
+        );
+        assert_eq!(newest.passes.len(), 3);
+        assert_eq!(newest.submitted_frame, 0);
+        // something in the merged results still has to be read back
+        assert!(!newest.dont_copy);
+    }
+}
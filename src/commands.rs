@@ -0,0 +1,180 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use bevy::{ecs::system::SystemParam, prelude::*, utils::HashMap};
+use bytemuck::Pod;
+
+use crate::{
+    events::{find_buffer, read_buffer},
+    ComputeEvent, ComputeRequestId, ComputeTrait,
+};
+
+/// Results of a dispatch, what [`ComputeComplete`](crate::ComputeComplete) would have applied to the app world
+pub struct ComputeOutput<T: ComputeTrait> {
+    pub request: ComputeRequestId,
+    /// copy of the resource after the staged fields were written, None if nothing was staged
+    pub data: Option<T>,
+    /// image data that was written to `Assets<Image>`
+    pub images: Vec<(Handle<Image>, Vec<u8>)>,
+    /// bytes copied back from [`ComputeShader::staged_buffers`](crate::ComputeShader::staged_buffers)
+    pub buffers: Vec<(u32, Vec<u8>)>,
+}
+
+impl<T: ComputeTrait> ComputeOutput<T> {
+    /// Bytes copied back from a staged `#[buffer]` binding
+    pub fn buffer_bytes(&self, binding: u32) -> Option<&[u8]> {
+        find_buffer(&self.buffers, binding)
+    }
+
+    /// Contents of a staged `#[buffer]` binding, any trailing bytes that don't fill a P are ignored
+    pub fn buffer<P: Pod>(&self, binding: u32) -> Option<Vec<P>> {
+        read_buffer(&self.buffers, binding)
+    }
+}
+
+struct TaskShared<T: ComputeTrait> {
+    done: bool,
+    result: Option<Option<ComputeOutput<T>>>,
+    waker: Option<Waker>,
+}
+
+/// Future that resolves once the dispatch has been read back into the app world,
+/// resolves to None if the request was dropped
+pub struct ComputeTask<T: ComputeTrait> {
+    request: ComputeRequestId,
+    shared: Arc<Mutex<TaskShared<T>>>,
+}
+
+impl<T: ComputeTrait> ComputeTask<T> {
+    pub fn request(&self) -> ComputeRequestId {
+        self.request
+    }
+}
+
+impl<T: ComputeTrait> Future for ComputeTask<T> {
+    type Output = Option<ComputeOutput<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        match shared.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Completes a [`ComputeTask`], if dropped first the task resolves to None
+pub(crate) struct ComputeWaiter<T: ComputeTrait> {
+    shared: Arc<Mutex<TaskShared<T>>>,
+}
+
+impl<T: ComputeTrait> ComputeWaiter<T> {
+    fn new(request: ComputeRequestId) -> (Self, ComputeTask<T>) {
+        let shared = Arc::new(Mutex::new(TaskShared {
+            done: false,
+            result: None,
+            waker: None,
+        }));
+        (
+            ComputeWaiter {
+                shared: shared.clone(),
+            },
+            ComputeTask { request, shared },
+        )
+    }
+
+    pub(crate) fn complete(self, output: ComputeOutput<T>) {
+        self.finish(Some(output));
+    }
+
+    fn finish(&self, output: Option<ComputeOutput<T>>) {
+        let mut shared = self.shared.lock().unwrap();
+        if shared.done {
+            return;
+        }
+        shared.done = true;
+        shared.result = Some(output);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: ComputeTrait> Drop for ComputeWaiter<T> {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+/// Tasks waiting on a dispatch, completed in listen_receiver
+#[derive(Resource)]
+pub struct ComputeWaiters<T: ComputeTrait> {
+    pub(crate) waiters: HashMap<ComputeRequestId, ComputeWaiter<T>>,
+}
+
+impl<T: ComputeTrait> Default for ComputeWaiters<T> {
+    fn default() -> Self {
+        Self {
+            waiters: HashMap::default(),
+        }
+    }
+}
+
+impl<T: ComputeTrait> ComputeWaiters<T> {
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    pub(crate) fn take(&mut self, request: &ComputeRequestId) -> Option<ComputeWaiter<T>> {
+        self.waiters.remove(request)
+    }
+}
+
+/// Sends [`ComputeEvent`]s and returns futures for the results
+/// ```ignore
+/// fn trigger(mut compute: ComputeCommands<Simple>) {
+///     let task = compute.dispatch(ComputeEvent::<Simple>::new(UVec3::new(4, 1, 1)));
+///     AsyncComputeTaskPool::get()
+///         .spawn(async move {
+///             if let Some(output) = task.await {
+///                 info!("{:?}", output.data);
+///             }
+///         })
+///         .detach();
+/// }
+/// ```
+#[derive(SystemParam)]
+pub struct ComputeCommands<'w, T: ComputeTrait> {
+    events: EventWriter<'w, ComputeEvent<T>>,
+    waiters: ResMut<'w, ComputeWaiters<T>>,
+}
+
+impl<'w, T: ComputeTrait> ComputeCommands<'w, T> {
+    /// Send the event, without waiting on the result
+    pub fn send(&mut self, event: ComputeEvent<T>) -> ComputeRequestId {
+        self.events.send(event).into()
+    }
+
+    /// Send the event, the returned future resolves once the results are back in the app world
+    pub fn dispatch(&mut self, event: ComputeEvent<T>) -> ComputeTask<T> {
+        // passes that would be skipped in extract never complete, resolve right away
+        let valid =
+            event.passes.is_empty() || event.passes.iter().any(|pass| pass.is_valid::<T>());
+
+        let request = self.send(event);
+        let (waiter, task) = ComputeWaiter::new(request);
+        if valid {
+            self.waiters.waiters.insert(request, waiter);
+        } else {
+            warn!("no valid passes for compute task {:?}", request);
+        }
+        task
+    }
+}
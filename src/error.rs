@@ -0,0 +1,70 @@
+use std::fmt;
+
+use bevy::prelude::*;
+
+/// Something that went wrong between sending a [`ComputeEvent`](crate::ComputeEvent) and the
+/// results arriving, delivered in the app world as [`ComputeFailed`](crate::ComputeFailed)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ComputeError {
+    /// an image used by the resource or a readback isn't loaded, it isn't copied back
+    MissingImage(AssetId<Image>),
+    /// an image can't be copied to a buffer, its usage needs `COPY_SRC` and a format
+    /// that can be copied with every aspect at once
+    ImageNotCopyable(AssetId<Image>),
+    /// the bind group couldn't be created, the dispatch is skipped
+    BindGroup(String),
+    /// a staging buffer couldn't be mapped, nothing from the dispatch is read back
+    MapFailed,
+    /// a pipeline couldn't be created, passes using it are skipped
+    Pipeline { entry: String, message: String },
+    /// the pipelines weren't ready when the dispatch was submitted, nothing ran
+    NotReady,
+    /// none of the event's passes were valid, see [`Pass::is_valid`](crate::Pass::is_valid),
+    /// nothing ran
+    InvalidPass,
+    /// a later event sent in the same frame replaced this one's passes, see
+    /// [`PassMerge::Replace`](crate::PassMerge::Replace), nothing ran
+    Superseded,
+    /// the dispatch ran, but the [`ComputeChannelPolicy`](crate::ComputeChannelPolicy) dropped
+    /// its results because the app world fell behind
+    Dropped,
+    /// an indirect pass points at a binding that isn't a buffer with `COPY_SRC` usage, or that
+    /// has no room for the arguments after the offset, nothing ran
+    InvalidIndirect(u32),
+}
+
+impl fmt::Display for ComputeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ComputeError::MissingImage(id) => write!(f, "image {:?} isn't loaded", id),
+            ComputeError::ImageNotCopyable(id) => write!(
+                f,
+                "image {:?} can't be copied, it needs COPY_SRC usage and a copyable format",
+                id
+            ),
+            ComputeError::BindGroup(message) => {
+                write!(f, "failed to create bind group: {}", message)
+            }
+            ComputeError::MapFailed => write!(f, "failed to map staging buffer"),
+            ComputeError::Pipeline { entry, message } => {
+                write!(f, "pipeline for entry point '{}' failed: {}", entry, message)
+            }
+            ComputeError::NotReady => write!(f, "pipelines weren't ready, passes didn't run"),
+            ComputeError::InvalidPass => {
+                write!(f, "no valid passes, check entry points, workgroups, iterations and push constants")
+            }
+            ComputeError::Superseded => {
+                write!(f, "replaced by a later event in the same frame, passes didn't run")
+            }
+            ComputeError::Dropped => write!(f, "results dropped, the compute channel was full"),
+            ComputeError::InvalidIndirect(binding) => write!(
+                f,
+                "binding {} can't be used for indirect dispatch, it needs a buffer with COPY_SRC \
+                 usage and 12 bytes after the offset",
+                binding
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ComputeError {}
@@ -1,32 +1,146 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, ops::Range};
 
-use bevy::{prelude::*, render::render_resource::ShaderRef};
+use bytemuck::Pod;
 
-use crate::{ComputeShader, ComputeTrait};
+use bevy::{
+    ecs::event::EventId,
+    math::URect,
+    prelude::*,
+    render::render_resource::{ShaderDefVal, ShaderRef, ShaderStages},
+};
+
+use crate::{ComputeError, ComputeShader, ComputeTrait};
+
+/// Identifies a [`ComputeEvent`], created from the [`EventId`] returned by `EventWriter::send`
+/// ```ignore
+/// let id: ComputeRequestId = compute_events.send(ComputeEvent::<Simple>::new(size)).into();
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ComputeRequestId(pub usize);
+
+impl<T: ComputeTrait> From<EventId<ComputeEvent<T>>> for ComputeRequestId {
+    fn from(id: EventId<ComputeEvent<T>>) -> Self {
+        ComputeRequestId(id.id)
+    }
+}
 
 /// Message to notify the App world that the compute has completed
 #[derive(Event)]
 pub struct ComputeComplete<T: ComputeTrait> {    
+    /// events that were dispatched together, events sent in the same frame share a dispatch
+    pub requests: Vec<ComputeRequestId>,
+    /// app world frame the events were extracted on
+    pub submitted_frame: u32,
+    /// app world frame the results were applied on
+    pub completed_frame: u32,
+    /// passes that were dispatched, after duplicates and invalid passes are removed
+    pub passes: Vec<Pass>,
 
-    // TODO: dont support yet, but need to
+    /// nothing was read back, the event used [`ComputeEvent::no_staging`]
     pub dont_copy: bool,
+    /// bytes copied back from [`ComputeShader::staged_buffers`], by binding index
+    pub buffers: Vec<(u32, Vec<u8>)>,
     pub _marker: PhantomData<T>,
 }
 
 impl<T: ComputeTrait> Default for ComputeComplete<T> {
     fn default() -> Self {
         ComputeComplete {
+            requests: Vec::new(),
+            submitted_frame: 0,
+            completed_frame: 0,
+            passes: Vec::new(),
             dont_copy: false,
+            buffers: Vec::new(),
+            _marker: Default::default(),
+        }
+    }
+}
+
+impl<T: ComputeTrait> ComputeComplete<T> {
+    /// Was this request part of the dispatch
+    pub fn contains(&self, id: ComputeRequestId) -> bool {
+        self.requests.contains(&id)
+    }
+
+    /// Number of frames between the dispatch and the results arriving
+    pub fn frames(&self) -> u32 {
+        self.completed_frame.wrapping_sub(self.submitted_frame)
+    }
+
+    /// Bytes copied back from a staged `#[buffer]` binding
+    pub fn buffer_bytes(&self, binding: u32) -> Option<&[u8]> {
+        find_buffer(&self.buffers, binding)
+    }
+
+    /// Contents of a staged `#[buffer]` binding, any trailing bytes that don't fill a P are ignored
+    pub fn buffer<P: Pod>(&self, binding: u32) -> Option<Vec<P>> {
+        read_buffer(&self.buffers, binding)
+    }
+}
+
+pub(crate) fn find_buffer(buffers: &[(u32, Vec<u8>)], binding: u32) -> Option<&[u8]> {
+    buffers
+        .iter()
+        .find(|(index, _)| *index == binding)
+        .map(|(_, bytes)| bytes.as_slice())
+}
+
+pub(crate) fn read_buffer<P: Pod>(buffers: &[(u32, Vec<u8>)], binding: u32) -> Option<Vec<P>> {
+    let bytes = find_buffer(buffers, binding)?;
+    let len = bytes.len() - bytes.len() % std::mem::size_of::<P>().max(1);
+    Some(bytemuck::pod_collect_to_vec(&bytes[..len]))
+}
+
+/// Sent in the app world when the pipelines for T are ready, events sent before then
+/// are held and dispatched once they are
+#[derive(Event)]
+pub struct ComputeReady<T: ComputeTrait> {
+    pub _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for ComputeReady<T> {
+    fn default() -> Self {
+        Self {
             _marker: Default::default(),
         }
     }
 }
 
+/// Sent in the app world when a dispatch couldn't run or be read back, any
+/// [`ComputeTask`](crate::ComputeTask) waiting on the requests resolves to None
+#[derive(Event, Clone, Debug)]
+pub struct ComputeFailed<T: ComputeTrait> {
+    /// requests that were affected, empty if the error isn't tied to a dispatch
+    pub requests: Vec<ComputeRequestId>,
+    pub error: ComputeError,
+    pub _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> ComputeFailed<T> {
+    pub fn new(requests: Vec<ComputeRequestId>, error: ComputeError) -> Self {
+        Self {
+            requests,
+            error,
+            _marker: PhantomData,
+        }
+    }
+}
+
 /// Event to trigger a compute shader, you can specify multiple passes and workgroups
 #[derive(Event, Clone)]
 pub struct ComputeEvent<T: ComputeTrait> {
     pub passes: Vec<Pass>,
+    /// skip copying and mapping staged buffers and images, only the passes are run
     pub no_staging: bool,
+    /// which staged bindings and images to read back
+    pub readback: Readback,
+    /// parts of staged images the passes modified, only these regions are copied back
+    pub regions: Vec<(AssetId<Image>, URect)>,
+    /// mip levels of staged images to copy back, only the first if not given
+    pub mip_levels: Vec<(AssetId<Image>, Range<u32>)>,
+    /// images to copy back that aren't staged storage textures
+    pub textures: Vec<Handle<Image>>,
     pub _marker: PhantomData<T>,
     
 }
@@ -35,11 +149,15 @@ pub struct ComputeEvent<T: ComputeTrait> {
 impl<T: ComputeTrait> Default for ComputeEvent<T> {
     fn default() -> Self {
         Self { 
-            passes: vec![Pass {
-                entry: T::entry_points().first().expect("no entry points"),
-                workgroups: vec![UVec3::new(1, 1, 1)],
-            }], 
+            passes: vec![Pass::new(
+                T::entry_points().first().expect("no entry points"),
+                UVec3::new(1, 1, 1),
+            )],
             no_staging: false,
+            readback: Readback::All,
+            regions: Vec::new(),
+            mip_levels: Vec::new(),
+            textures: Vec::new(),
             _marker: Default::default()
          }
     }
@@ -49,24 +167,17 @@ impl<T: ComputeTrait> ComputeEvent<T> {
     pub fn new(workgroups: UVec3) -> Self {        
         ComputeEvent::<T> {
 
-            passes: vec![
-                Pass {
-                    entry: T::entry_points().first().expect("no entry points"),
-                    workgroups: vec![workgroups],
-                }
-            ],
+            passes: vec![Pass::new(
+                T::entry_points().first().expect("no entry points"),
+                workgroups,
+            )],
             ..default()
         }
     }
 
     pub fn new_named(name: &'static str, workgroups: UVec3) -> Self {        
         ComputeEvent::<T> {
-            passes: vec![
-                Pass {
-                    entry: name,
-                    workgroups: vec![workgroups],
-                }
-            ],
+            passes: vec![Pass::new(name, workgroups)],
             ..default()
         }
     }
@@ -74,12 +185,43 @@ impl<T: ComputeTrait> ComputeEvent<T> {
 
     pub fn new_xyz( x: u32, y: u32, z: u32) -> Self {        
         ComputeEvent::<T> {
-            passes: vec![
-                Pass {
-                    entry: T::entry_points().first().expect("no entry points"),
-                    workgroups: vec![UVec3::new(x, y, z)],
-                }
-            ],
+            passes: vec![Pass::new(
+                T::entry_points().first().expect("no entry points"),
+                UVec3::new(x, y, z),
+            )],
+            ..default()
+        }
+    }
+
+    /// Cover invocations with the first entry point, rounded up to whole workgroups
+    pub fn for_invocations(invocations: UVec3) -> Self {
+        ComputeEvent::<T> {
+            passes: vec![Pass::for_invocations(
+                T::entry_points().first().expect("no entry points"),
+                invocations,
+            )],
+            ..default()
+        }
+    }
+
+    /// One invocation per texel of image with the first entry point, rounded up to whole workgroups
+    pub fn for_image(image: &Handle<Image>) -> Self {
+        ComputeEvent::<T> {
+            passes: vec![Pass::for_image(
+                T::entry_points().first().expect("no entry points"),
+                image,
+            )],
+            ..default()
+        }
+    }
+
+    /// Read back without running any passes, like at the end of a brush stroke that was
+    /// dispatched with `no_staging`. Images are copied as the gpu has them, without
+    /// [`ComputePlugin::with_persistent`](crate::ComputePlugin::with_persistent) buffers are
+    /// uploaded from T again first
+    pub fn no_passes() -> Self {
+        ComputeEvent::<T> {
+            passes: Vec::new(),
             ..default()
         }
     }
@@ -89,10 +231,139 @@ impl<T: ComputeTrait> ComputeEvent<T> {
         self
     }
 
+    /// Add a pass that reads its workgroup count from a buffer binding, see [`Pass::indirect`]
+    pub fn add_indirect_pass(
+        &mut self,
+        entry: &'static str,
+        binding: u32,
+        offset: u64,
+    ) -> &mut Self {
+        self.passes.push(Pass::indirect(entry, binding, offset));
+        self
+    }
+
+    /// Add a pass that runs its workgroups `iterations` times in a row, for relaxation or blurs
+    pub fn add_iterated_pass(
+        &mut self,
+        entry: &'static str,
+        workgroup: UVec3,
+        iterations: u32,
+    ) -> &mut Self {
+        self.passes
+            .push(Pass::new(entry, workgroup).with_iterations(iterations));
+        self
+    }
+
     pub fn no_staging(mut self) -> Self {
         self.no_staging = true;
         self
     }
+
+    /// Only read back these `staging` storage bindings, by binding index
+    pub fn readback_bindings(mut self, bindings: &[u32]) -> Self {
+        self.readback_only().0.extend_from_slice(bindings);
+        self
+    }
+
+    /// Only read back this `staging` image
+    pub fn readback_image(mut self, image: &Handle<Image>) -> Self {
+        self.readback_only().1.push(image.id());
+        self
+    }
+
+    /// Only copy back this region of a staged image, in pixels
+    pub fn readback_region(mut self, image: &Handle<Image>, region: URect) -> Self {
+        self.regions.push((image.id(), region));
+        self
+    }
+
+    /// Copy back these mip levels of a staged image, every array layer or 3d slice is included.
+    /// A region only applies to the first mip level
+    pub fn readback_mip_levels(mut self, image: &Handle<Image>, mip_levels: Range<u32>) -> Self {
+        self.mip_levels.push((image.id(), mip_levels));
+        self
+    }
+
+    /// Copy back an image that isn't a staged storage texture, like a `#[texture]` binding or a
+    /// camera render target, after the compute node runs. The image needs `COPY_SRC` in its
+    /// texture descriptor usage.
+    ///
+    /// The copy happens in [`ComputeNode`](crate::ComputeNode), so what it sees depends on where
+    /// `set_nodes` puts it. Before `CameraDriverLabel`, like the examples, a render target still
+    /// has the previous frame's contents, add an edge from `CameraDriverLabel` to the node to
+    /// read the current frame instead
+    pub fn readback_texture(mut self, image: &Handle<Image>) -> Self {
+        self.textures.push(image.clone());
+        self
+    }
+
+    fn readback_only(&mut self) -> (&mut Vec<u32>, &mut Vec<AssetId<Image>>) {
+        if self.readback == Readback::All {
+            self.readback = Readback::Only {
+                bindings: Vec::new(),
+                images: Vec::new(),
+            };
+        }
+        match &mut self.readback {
+            Readback::Only { bindings, images } => (bindings, images),
+            Readback::All => unreachable!(),
+        }
+    }
+}
+
+/// Which `staging` bindings and images are read back after the passes run
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Readback {
+    /// everything marked `staging`
+    #[default]
+    All,
+    /// only these storage bindings and images
+    Only {
+        bindings: Vec<u32>,
+        images: Vec<AssetId<Image>>,
+    },
+}
+
+impl Readback {
+    pub fn binding(&self, index: u32) -> bool {
+        match self {
+            Readback::All => true,
+            Readback::Only { bindings, .. } => bindings.contains(&index),
+        }
+    }
+
+    pub fn image(&self, id: AssetId<Image>) -> bool {
+        match self {
+            Readback::All => true,
+            Readback::Only { images, .. } => images.contains(&id),
+        }
+    }
+
+    /// Combine readbacks from events sent in the same frame
+    pub fn merge(&mut self, other: &Readback) {
+        match (&mut *self, other) {
+            (Readback::All, _) => {}
+            (_, Readback::All) => *self = Readback::All,
+            (
+                Readback::Only { bindings, images },
+                Readback::Only {
+                    bindings: other_bindings,
+                    images: other_images,
+                },
+            ) => {
+                for binding in other_bindings {
+                    if !bindings.contains(binding) {
+                        bindings.push(*binding);
+                    }
+                }
+                for image in other_images {
+                    if !images.contains(image) {
+                        images.push(*image);
+                    }
+                }
+            }
+        }
+    }
 }
 
 /// A pass to run a compute shader
@@ -103,6 +374,60 @@ pub struct Pass {
 
     /// workgroup sizes to run for entry point
     pub workgroups: Vec<UVec3>,
+
+    /// how many times the workgroups are dispatched, in order
+    pub iterations: u32,
+
+    /// push constant bytes written at offset 0 before the pass dispatches,
+    /// must fit in one of [`ComputeShader::push_constant_ranges`]
+    pub push_constants: Vec<u8>,
+
+    /// push constant offset to write the iteration index to, as a u32, before each iteration
+    pub iteration_push_constant: Option<u32>,
+
+    /// dispatch with arguments read from a buffer binding instead of `workgroups`
+    pub indirect: Option<IndirectDispatch>,
+
+    /// work to cover instead of `workgroups`, sized from the entry point's `@workgroup_size`
+    /// when the pass is extracted
+    pub size: Option<DispatchSize>,
+
+    /// shader defs on top of [`ComputeShader::shader_defs`], passes with any use
+    /// a specialized pipeline that is compiled the first time it's needed
+    pub shader_defs: Vec<ShaderDefVal>,
+}
+
+/// Work a pass covers, divided by the reflected `@workgroup_size` and rounded up
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DispatchSize {
+    /// total invocations in each dimension
+    Invocations(UVec3),
+    /// one invocation per texel of the image, array layers or depth in z
+    Image(AssetId<Image>),
+}
+
+/// Where [`Pass::indirect`] reads its [`DispatchIndirectArgs`] from
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct IndirectDispatch {
+    /// binding of a buffer created with `COPY_SRC` usage, see [`indirect_args_buffer`](crate::indirect_args_buffer)
+    pub binding: u32,
+    /// byte offset of the arguments in the buffer, a multiple of 4
+    pub offset: u64,
+}
+
+impl Default for Pass {
+    fn default() -> Self {
+        Pass {
+            entry: "main",
+            workgroups: vec![UVec3::ONE],
+            iterations: 1,
+            push_constants: Vec::new(),
+            iteration_push_constant: None,
+            indirect: None,
+            size: None,
+            shader_defs: Vec::new(),
+        }
+    }
 }
 
 impl Pass {
@@ -110,8 +435,109 @@ impl Pass {
         Pass {
             entry,
             workgroups: vec![workgroups],
+            ..default()
+        }
+    }
+
+    /// A pass whose workgroup count was written by an earlier pass, read from the buffer at
+    /// binding, like processing only the particles a compaction pass kept
+    pub fn indirect(entry: &'static str, binding: u32, offset: u64) -> Self {
+        Pass {
+            entry,
+            workgroups: Vec::new(),
+            indirect: Some(IndirectDispatch { binding, offset }),
+            ..default()
+        }
+    }
+
+    /// A pass covering invocations, the workgroup count comes from the shader's `@workgroup_size`
+    pub fn for_invocations(entry: &'static str, invocations: UVec3) -> Self {
+        Pass {
+            entry,
+            workgroups: Vec::new(),
+            size: Some(DispatchSize::Invocations(invocations)),
+            ..default()
         }
     }
+
+    /// A pass with one invocation per texel of image
+    pub fn for_image(entry: &'static str, image: &Handle<Image>) -> Self {
+        Pass {
+            entry,
+            workgroups: Vec::new(),
+            size: Some(DispatchSize::Image(image.id())),
+            ..default()
+        }
+    }
+
+    pub fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Push constants for this pass, like a seed or tile offset, no need to rebuild the bind group
+    pub fn with_push_constants<P: Pod>(mut self, data: &P) -> Self {
+        self.push_constants = bytemuck::bytes_of(data).to_vec();
+        self
+    }
+
+    /// Compile this pass with an extra shader def, like `"USE_F32"` or
+    /// `ShaderDefVal::UInt("BRUSH_MODE".into(), 2)`
+    pub fn with_shader_def(mut self, def: impl Into<ShaderDefVal>) -> Self {
+        self.shader_defs.push(def.into());
+        self
+    }
+
+    /// Write the iteration index as a u32 push constant at offset before each iteration
+    pub fn with_iteration_push_constant(mut self, offset: u32) -> Self {
+        self.iteration_push_constant = Some(offset);
+        self
+    }
+
+    /// Entry point exists, nothing would dispatch zero workgroups, and push constants fit
+    pub fn is_valid<T: ComputeShader>(&self) -> bool {
+        T::entry_points().contains(&self.entry)
+            && self.iterations > 0
+            && self
+                .workgroups
+                .iter()
+                .all(|workgroup| workgroup.x > 0 && workgroup.y > 0 && workgroup.z > 0)
+            && self.push_constants_fit::<T>()
+            && self
+                .indirect
+                .map_or(true, |indirect| indirect.offset % 4 == 0)
+    }
+
+    // wgpu wants push constants in 4 byte steps, inside a range visible to compute
+    fn push_constants_fit<T: ComputeShader>(&self) -> bool {
+        let fits = |start: u32, len: u32| {
+            start % 4 == 0
+                && len % 4 == 0
+                && T::push_constant_ranges().iter().any(|range| {
+                    range.stages.contains(ShaderStages::COMPUTE)
+                        && range.range.start <= start
+                        && start + len <= range.range.end
+                })
+        };
+        (self.push_constants.is_empty() || fits(0, self.push_constants.len() as u32))
+            && self
+                .iteration_push_constant
+                .map_or(true, |offset| fits(offset, 4))
+    }
+}
+
+/// How passes from several [`ComputeEvent`]s sent in the same frame are combined into one dispatch
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PassMerge {
+    /// only the last event with passes runs, earlier ones are reported as
+    /// [`ComputeError::Superseded`](crate::ComputeError::Superseded) and not read back
+    Replace,
+    /// every pass of every event runs, in the order the events were sent
+    Append,
+    /// like append, but a pass identical to one from an earlier event is skipped,
+    /// repeats within one event still run
+    #[default]
+    Union,
 }
 
 
@@ -142,7 +568,8 @@ pub fn shader_modified<T: ComputeShader + ComputeTrait>(
             let id = match T::shader() {
                 ShaderRef::Handle(handle) => handle.id(),
                 ShaderRef::Path(path) => asset_server.load(path).id(),
-                _ => todo!(),
+                // reported as a pipeline error when the pipelines are created
+                ShaderRef::Default => return,
             };
             *asset_id_option = Some(id.clone());
             id
@@ -163,4 +590,60 @@ pub fn shader_modified<T: ComputeShader + ComputeTrait>(
         notify_events.send(ComputeShaderModified::<T>::default());
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Simple;
+
+    #[test]
+    fn passes_need_an_entry_point_iterations_and_workgroups() {
+        let pass = Pass::new("main", UVec3::ONE);
+        assert!(pass.is_valid::<Simple>());
+        assert!(!Pass::new("missing", UVec3::ONE).is_valid::<Simple>());
+        assert!(!Pass::new("main", UVec3::new(1, 0, 1)).is_valid::<Simple>());
+        assert!(!pass.with_iterations(0).is_valid::<Simple>());
+    }
+
+    #[test]
+    fn push_constants_fit_the_ranges() {
+        // Simple has one compute range, 0..16
+        let pass = Pass::new("main", UVec3::ONE);
+        assert!(pass.push_constants_fit::<Simple>());
+        assert!(pass.clone().with_push_constants(&[1u32; 4]).push_constants_fit::<Simple>());
+        assert!(!pass.clone().with_push_constants(&[1u32; 5]).push_constants_fit::<Simple>());
+        assert!(!pass.clone().with_push_constants(&[1u8; 6]).push_constants_fit::<Simple>());
+
+        assert!(pass.clone().with_iteration_push_constant(12).push_constants_fit::<Simple>());
+        assert!(!pass.clone().with_iteration_push_constant(16).push_constants_fit::<Simple>());
+        assert!(!pass.clone().with_iteration_push_constant(2).push_constants_fit::<Simple>());
+    }
+
+    #[test]
+    fn indirect_passes_need_aligned_offsets() {
+        assert!(Pass::indirect("main", 3, 0).is_valid::<Simple>());
+        assert!(Pass::indirect("main", 3, 12).is_valid::<Simple>());
+        assert!(!Pass::indirect("main", 3, 2).is_valid::<Simple>());
+        assert!(!Pass::indirect("missing", 3, 0).is_valid::<Simple>());
+    }
+
+    #[test]
+    fn readback_merge_keeps_everything_either_asked_for() {
+        let image = AssetId::<Image>::default();
+        let only = |bindings: &[u32], images: &[AssetId<Image>]| Readback::Only {
+            bindings: bindings.to_vec(),
+            images: images.to_vec(),
+        };
+
+        let mut readback = only(&[1], &[]);
+        readback.merge(&only(&[2, 1], &[image]));
+        assert_eq!(readback, only(&[1, 2], &[image]));
+
+        readback.merge(&Readback::All);
+        assert_eq!(readback, Readback::All);
+
+        readback.merge(&only(&[3], &[]));
+        assert_eq!(readback, Readback::All);
+    }
+}
@@ -0,0 +1,52 @@
+use bevy::{
+    prelude::*,
+    render::{
+        render_resource::{Buffer, BufferInitDescriptor, BufferUsages, ShaderType},
+        renderer::RenderDevice,
+    },
+};
+use bytemuck::{Pod, Zeroable};
+
+/// Shader import with a matching `DispatchIndirectArgs` struct and helpers to fill it
+/// ```wgsl
+/// #import bevy_sly_compute::indirect::{DispatchIndirectArgs, dispatch_args_for}
+///
+/// @group(0) @binding(2) var<storage, read_write> args: DispatchIndirectArgs;
+/// ```
+pub const INDIRECT_SHADER_HANDLE: Handle<Shader> =
+    Handle::weak_from_u128(0x5a1c_0de7_1d1e_4c7a_9b3f_2e6d_8a41_f2c3);
+
+/// Arguments for `dispatch_workgroups_indirect`, the workgroup count in each dimension
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Pod, Zeroable, ShaderType)]
+#[repr(C)]
+pub struct DispatchIndirectArgs {
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+}
+
+impl DispatchIndirectArgs {
+    pub fn new(workgroups: UVec3) -> Self {
+        Self {
+            x: workgroups.x,
+            y: workgroups.y,
+            z: workgroups.z,
+        }
+    }
+}
+
+/// Buffer a pass can write [`DispatchIndirectArgs`] into and a later
+/// [`Pass::indirect`](crate::Pass::indirect) can dispatch from, bind it with `#[storage(n, buffer)]`.
+/// A storage binding can't also be used as `INDIRECT` in the same dispatch, so the node copies
+/// the arguments into a buffer of its own before each indirect pass, which needs `COPY_SRC`
+pub fn indirect_args_buffer(
+    render_device: &RenderDevice,
+    label: Option<&str>,
+    args: &[DispatchIndirectArgs],
+) -> Buffer {
+    render_device.create_buffer_with_data(&BufferInitDescriptor {
+        label,
+        contents: bytemuck::cast_slice(args),
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+    })
+}
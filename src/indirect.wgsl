@@ -0,0 +1,19 @@
+#define_import_path bevy_sly_compute::indirect
+
+// Arguments for an indirect dispatch, matches DispatchIndirectArgs on the rust side.
+// Write them from an earlier pass, they are copied out of the binding when the indirect pass starts
+struct DispatchIndirectArgs {
+    x: u32,
+    y: u32,
+    z: u32,
+}
+
+// Workgroups needed to cover count invocations
+fn workgroups_for(count: u32, workgroup_size: u32) -> u32 {
+    return (count + workgroup_size - 1u) / workgroup_size;
+}
+
+// Arguments to run one invocation per item, for a 1d workgroup size
+fn dispatch_args_for(count: u32, workgroup_size: u32) -> DispatchIndirectArgs {
+    return DispatchIndirectArgs(workgroups_for(count, workgroup_size), 1u, 1u);
+}
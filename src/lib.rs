@@ -1,9 +1,19 @@
 #![feature(iter_collect_into)]
 
 mod traits;
-use std::marker::PhantomData;
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use channel::{create_compute_channels, ComputeMessage, ComputeReceiver, ComputeSender};
+use channel::{
+    create_compute_channels, create_error_channels, ComputeErrorReceiver, ComputeErrorSender,
+    ComputeMessage, ComputeReceiver, ComputeSender,
+};
+pub use channel::{ComputeChannelPolicy, ImageData};
 pub use traits::*;
 
 mod node;
@@ -17,14 +27,34 @@ pub use events::*;
 
 mod channel;
 
+mod commands;
+pub use commands::*;
+
+mod error;
+pub use error::*;
+
+mod indirect;
+pub use indirect::*;
+
+mod workgroup;
+pub use workgroup::*;
+
 use bevy::{
+    asset::{load_internal_asset, LoadState},
+    core::FrameCount,
+    ecs::system::SystemChangeTick,
+    math::URect,
     prelude::*,
     render::{
         render_asset::RenderAssets,
         render_graph::RenderGraph,
-        render_resource::{BufferDescriptor, BufferUsages, Maintain, MapMode},
-        renderer::RenderDevice,
-        texture::{FallbackImage, TextureFormatPixelInfo},
+        render_resource::{
+            AsBindGroupError, BindGroupEntry, BufferDescriptor, BufferUsages, CachedPipelineState,
+            Maintain, MapMode, OwnedBindingResource, PipelineCache, PipelineCacheError, ShaderDefVal,
+            TextureUsages, COPY_BYTES_PER_ROW_ALIGNMENT,
+        },
+        renderer::{RenderDevice, RenderQueue},
+        texture::FallbackImage,
         Extract, Render, RenderApp, RenderSet,
     },
 };
@@ -32,11 +62,18 @@ use bevy::{
 /// Helper module to import most used elements.
 pub mod prelude {
     pub use crate::{
+        commands::*,
+        error::*,
+        indirect::*,
+        workgroup::WorkgroupSizes,
         events::{Pass, *},
         mark_shader_modified,
         node::*,
+        read_staged_fields,
+        shader_def_fields,
         traits::*,
-        ComputePlugin, MainComputePlugin,
+        compute_ready, ComputeChannelPolicy, ComputePlugin, ComputeStatus, MainComputePlugin,
+        PipelineStatus,
     };
     // Since these are always used when using this crate
     pub use bevy::render::render_resource::{ShaderRef, ShaderType};
@@ -51,6 +88,9 @@ impl Plugin for MainComputePlugin {
             return;
         }
 
+        // shader import for writing indirect dispatch arguments
+        load_internal_asset!(app, INDIRECT_SHADER_HANDLE, "indirect.wgsl", Shader::from_wgsl);
+
         // HACK: update StandardMaterial when any images are modified
         // currently have no way to update a material that shares an image
         // this is a workaround to mark all materials as modified
@@ -62,12 +102,75 @@ impl Plugin for MainComputePlugin {
 }
 
 pub struct ComputePlugin<T: ComputeTrait> {
+    /// How many dispatches can be waiting on readback before the render world waits on the gpu
+    pub frames_in_flight: usize,
+    /// Mark the resource as changed when staged fields are read back
+    pub change_detection: bool,
+    /// Keep gpu buffers and the bind group between dispatches, see [`ComputeShader::persistent_bindings`]
+    pub persistent: bool,
+    /// What to do with results when the app world falls behind
+    pub channel_policy: ComputeChannelPolicy,
+    /// How passes from events sent in the same frame are combined
+    pub pass_merge: PassMerge,
     _marker: PhantomData<T>,
 }
 
 impl<T: ComputeTrait> Default for ComputePlugin<T> {
     fn default() -> Self {
         ComputePlugin {
+            frames_in_flight: 2,
+            change_detection: false,
+            persistent: false,
+            channel_policy: ComputeChannelPolicy::default(),
+            pass_merge: PassMerge::default(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> ComputePlugin<T> {
+    pub fn with_frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        self.frames_in_flight = frames_in_flight;
+        self
+    }
+
+    /// Trigger change detection on T when staged fields are read back, off by default
+    /// since systems that dispatch on `resource_changed::<T>` would run every readback
+    pub fn with_change_detection(mut self, change_detection: bool) -> Self {
+        self.change_detection = change_detection;
+        self
+    }
+
+    /// Keep the gpu buffers and bind group alive between dispatches, only bindings that changed
+    /// in the app world are uploaded again, see [`ComputeShader::binding_changed`]. Bindings in
+    /// [`ComputeShader::persistent_bindings`] are never uploaded again, so their contents can stay
+    /// on the gpu. Staged fields written back by a readback don't count as a change
+    pub fn with_persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// What the render world does with a result when the app world hasn't read the previous
+    /// ones yet, [`ComputeChannelPolicy::Unbounded`] by default so nothing is lost
+    pub fn with_channel_policy(mut self, channel_policy: ComputeChannelPolicy) -> Self {
+        self.channel_policy = channel_policy;
+        self
+    }
+
+    /// How passes from several events sent in the same frame are combined,
+    /// [`PassMerge::Union`] by default
+    pub fn with_pass_merge(mut self, pass_merge: PassMerge) -> Self {
+        self.pass_merge = pass_merge;
+        self
+    }
+
+    fn settings(&self) -> ComputeSettings<T> {
+        ComputeSettings {
+            frames_in_flight: self.frames_in_flight.max(1),
+            change_detection: self.change_detection,
+            persistent: self.persistent,
+            channel_policy: self.channel_policy,
+            pass_merge: self.pass_merge,
             _marker: PhantomData,
         }
     }
@@ -80,20 +183,58 @@ impl<T: ComputeTrait> Plugin for ComputePlugin<T> {
         }
 
         // we need some way to safely send data from main app from render app
-        let (sender, receiver) = create_compute_channels::<T>();
+        let (sender, receiver) =
+            create_compute_channels::<T>(self.frames_in_flight, self.channel_policy);
+        let (error_sender, error_receiver) = create_error_channels::<T>();
+
+        // staging buffers are shared, the app world returns them once read
+        let pool = StagingBufferPool::<T>::new(self.frames_in_flight.max(1) + 1);
+
+        // the node writes the pipeline status, the app world copies it into ComputeStatus
+        let status = SharedComputeStatus::<T>::default();
 
         app.insert_resource(receiver)
+            .insert_resource(error_receiver)
+            .insert_resource(status.clone())
+            .init_resource::<ComputeStatus<T>>()
+            .add_systems(First, update_status::<T>)
+            .insert_resource(self.settings())
+            .insert_resource(pool.clone())
+            // free pooled staging buffers if the resource goes away
+            .add_systems(Last, clear_staging_pool::<T>.run_if(resource_removed::<T>()))
             .add_event::<ComputeEvent<T>>()
             .add_event::<ComputeComplete<T>>()
+            .add_event::<ComputeFailed<T>>()
+            .add_event::<ComputeReady<T>>()
+            .add_systems(Last, listen_errors::<T>)
+            .init_resource::<ComputeWaiters<T>>()
+            .init_resource::<ChangedBindings<T>>()
             .add_systems(Last, listen_receiver::<T>.run_if(resource_exists::<T>))
             // build event for shader modified
             .add_event::<ComputeShaderModified<T>>()
+            .init_resource::<WorkgroupSizes<T>>()
+            .add_systems(Update, update_workgroup_sizes::<T>)
             .add_systems(Update, events::shader_modified::<T>);
 
+        if self.persistent {
+            app.add_systems(
+                Last,
+                track_changed_bindings::<T>
+                    .after(listen_receiver::<T>)
+                    .run_if(resource_exists::<T>),
+            );
+        }
+
         let render_app = app.sub_app_mut(RenderApp);
 
         render_app
             .insert_resource(sender)
+            .insert_resource(error_sender)
+            .insert_resource(status)
+            .insert_resource(self.settings())
+            .insert_resource(pool)
+            .init_resource::<ComputeReadbacks<T>>()
+            .init_resource::<PendingComputeEvents<T>>()
             // checks for compute events and extracts the main resource into the render world
             // also grabs image handles and dimensions for later use
             .add_systems(ExtractSchedule, extract_resource::<T>)
@@ -106,11 +247,13 @@ impl<T: ComputeTrait> Plugin for ComputePlugin<T> {
             .add_systems(
                 Render,
                 (
-                    // the reads staging buffers and sends the data to the app world
-                    read_and_send::<T>
-                        .run_if(resource_exists::<RenderComputePasses<T>>)
-                        .in_set(RenderSet::Cleanup),
-                ),
+                    // start mapping staging buffers for this frames dispatch
+                    start_readback::<T>.run_if(resource_exists::<RenderComputePasses<T>>),
+                    // send any readbacks that have finished to the app world
+                    poll_readbacks::<T>,
+                )
+                    .chain()
+                    .in_set(RenderSet::Cleanup),
             );
 
         // add nodes to render graph
@@ -133,31 +276,218 @@ pub fn mark_shader_modified<R: Asset>(mut assets: ResMut<Assets<R>>) {
     }
 }
 
+fn clear_staging_pool<T: ComputeTrait>(pool: Res<StagingBufferPool<T>>) {
+    pool.clear();
+}
+
+fn update_status<T: ComputeTrait>(
+    shared: Res<SharedComputeStatus<T>>,
+    mut status: ResMut<ComputeStatus<T>>,
+    mut ready_events: EventWriter<ComputeReady<T>>,
+) {
+    let current = shared.get();
+    if status.status != current {
+        if current == PipelineStatus::Ready {
+            ready_events.send(ComputeReady::default());
+        }
+        status.status = current;
+    }
+}
+
+/// Run condition that is true once the pipelines for T are ready
+/// ```ignore
+/// app.add_systems(Update, trigger_compute.run_if(compute_ready::<Simple>()));
+/// ```
+pub fn compute_ready<T: ComputeTrait>(
+) -> impl FnMut(Option<Res<ComputeStatus<T>>>) -> bool + Clone {
+    move |status: Option<Res<ComputeStatus<T>>>| status.is_some_and(|status| status.is_ready())
+}
+
+// Forward errors from the render world, tasks waiting on a failed dispatch resolve to None
+fn listen_errors<T: ComputeTrait>(
+    receiver: Res<ComputeErrorReceiver<T>>,
+    mut failed_events: EventWriter<ComputeFailed<T>>,
+    mut waiters: ResMut<ComputeWaiters<T>>,
+) {
+    for failed in receiver.try_iter() {
+        for request in failed.requests.iter() {
+            waiters.take(request);
+        }
+        failed_events.send(failed);
+    }
+}
+
 fn listen_receiver<T: ComputeTrait>(
     mut data: ResMut<T>,
     receiver: Res<ComputeReceiver<T>>,
     //mut has_received_time: Local<bool>,
     mut complete_events: EventWriter<ComputeComplete<T>>,
+    mut failed_events: EventWriter<ComputeFailed<T>>,
     mut asset_event: EventWriter<AssetEvent<Image>>,
     mut images: ResMut<Assets<Image>>,
+    mut waiters: ResMut<ComputeWaiters<T>>,
+    settings: Res<ComputeSettings<T>>,
+    pool: Res<StagingBufferPool<T>>,
+    frame_count: Res<FrameCount>,
+    mut changed_bindings: ResMut<ChangedBindings<T>>,
+    system_tick: SystemChangeTick,
 ) {
-    if let Ok(msg) = receiver.try_recv() {
-        // So this is a bit of a hack, most of the time its image data changing, and we dont know if what on T has changed nice our copy
-        // was taken, so dont over write it if you dont have to
-        if let Some(d) = msg.data {
-            *data.bypass_change_detection() = d;
+    // changes made on the cpu still have to be uploaded by persistent mode, checked before
+    // any result is written
+    let changed_on_cpu = data.is_changed();
+
+    // apply every result that has arrived, pipelined rendering can finish more than one
+    // dispatch before Last runs. The render world sends in submission order, so each
+    // dispatch gets exactly one ComputeComplete, in order
+    for msg in receiver.try_iter() {
+        // any tasks waiting on this dispatch
+        let waiting = msg
+            .requests
+            .iter()
+            .filter_map(|request| waiters.take(request).map(|waiter| (*request, waiter)))
+            .collect::<Vec<_>>();
+
+        // raw buffers have no field to write into, their bytes are sent along with the results
+        let buffers = msg
+            .storage
+            .iter()
+            .filter(|(index, _)| T::staged_buffers().contains(index))
+            .map(|(index, buffer)| (*index, buffer.slice(..).get_mapped_range().to_vec()))
+            .collect::<Vec<_>>();
+
+        // Write only the staged fields, anything changed on the cpu while the dispatch
+        // was in flight is kept
+        let has_data = msg.storage.len() > buffers.len();
+        if has_data {
+            let data = if settings.change_detection {
+                &mut *data
+            } else {
+                data.bypass_change_detection()
+            };
+
+            // fields from ComputeShader::staged_field are decoded with encase, the rest by the macro
+            let (fields, storage): (Vec<_>, Vec<_>) = msg
+                .storage
+                .iter()
+                .filter(|(index, _)| !T::staged_buffers().contains(index))
+                .partition(|(index, _)| data.staged_field(*index).is_some());
+            let storage_buffer_slices = storage
+                .iter()
+                .map(|(index, buffer)| (*index, buffer.slice(..)))
+                .collect::<Vec<_>>();
+
+            // the snapshot persistent mode compares against gets them too, the gpu already
+            // has these so they aren't a change to upload
+            let snapshot = changed_bindings.snapshot.as_mut();
+            for target in std::iter::once(data).chain(snapshot) {
+                for (index, buffer) in fields.iter() {
+                    if let Some(field) = target.staged_field(*index) {
+                        field.read(&buffer.slice(..).get_mapped_range());
+                    }
+                }
+                if !storage_buffer_slices.is_empty() {
+                    target.map_storage_mappings(&storage_buffer_slices);
+                }
+            }
+            drop(storage_buffer_slices);
         }
 
-        // update images
-        for (handle, image_data) in msg.images {
-            let image = images.get_mut(&handle).unwrap();
-            image.data = image_data;
+        // return the staging buffers so the render world can reuse them
+        for (_, buffer) in msg.storage {
+            buffer.unmap();
+            pool.release(buffer);
+        }
+
+        // update images, patch in each mip level and region that was copied
+        let mut updated_images = Vec::with_capacity(msg.images.len());
+        for (handle, mips) in msg.images {
+            let Some(image) = images.get_mut(&handle) else {
+                // removed while the dispatch was in flight
+                failed_events.send(ComputeFailed::new(
+                    msg.requests.clone(),
+                    ComputeError::MissingImage(handle.id()),
+                ));
+                continue;
+            };
+            for mip in mips {
+                mip.write_to(image);
+            }
             asset_event.send(AssetEvent::Modified { id: handle.id() });
+            updated_images.push(handle);
+        }
+
+        // tasks get their own copy of the image data
+        let task_images = if waiting.is_empty() {
+            Vec::new()
+        } else {
+            updated_images
+                .iter()
+                .filter_map(|handle| {
+                    images
+                        .get(handle)
+                        .map(|image| (handle.clone_weak(), image.data.clone()))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        for (request, waiter) in waiting {
+            waiter.complete(ComputeOutput {
+                request,
+                data: has_data.then(|| data.clone()),
+                images: task_images.clone(),
+                buffers: buffers.clone(),
+            });
         }
-        complete_events.send(ComputeComplete::<T>::default());
+        complete_events.send(ComputeComplete::<T> {
+            requests: msg.requests,
+            submitted_frame: msg.submitted_frame,
+            completed_frame: frame_count.0,
+            passes: msg.passes,
+            dont_copy: msg.dont_copy,
+            buffers,
+            ..default()
+        });
+    }
+
+    // only our readbacks changed T, so persistent mode has nothing to upload
+    if data.is_changed() && !changed_on_cpu {
+        changed_bindings.staged_tick = Some(system_tick.this_run());
     }
 }
 
+// Compares each binding of T with the last snapshot, so persistent mode only uploads the
+// bindings that changed
+fn track_changed_bindings<T: ComputeTrait>(
+    data: Res<T>,
+    mut changed_bindings: ResMut<ChangedBindings<T>>,
+    render_device: Res<RenderDevice>,
+    mut bindings: Local<Option<Vec<u32>>>,
+) {
+    changed_bindings.changed.clear();
+    if !data.is_changed() || changed_bindings.staged_tick == Some(data.last_changed()) {
+        return;
+    }
+
+    // the layout doesn't change, so only ask once
+    let bindings = bindings.get_or_insert_with(|| {
+        T::bind_group_layout_entries(&render_device)
+            .iter()
+            .map(|entry| entry.binding)
+            .collect()
+    });
+
+    let changed = match &changed_bindings.snapshot {
+        Some(snapshot) => bindings
+            .iter()
+            .copied()
+            .filter(|binding| data.binding_changed(snapshot, *binding))
+            .collect(),
+        None => bindings.clone(),
+    };
+    changed_bindings.changed = changed;
+    changed_bindings.snapshot = Some(data.clone());
+}
+
 // Based on ExtractResourcePlugin::<T>::default(), but we only want extract when we have a ComputeEvent
 // Also extract passes from ComputeEvent into RenderComputePasses, and grab image handles and dimensions
 // TODO: make a new trait? We are doing more work in extract than we should
@@ -167,51 +497,215 @@ pub fn extract_resource<T: ComputeTrait>(
     main_resource: Extract<Option<Res<T::Source>>>,
     target_resource: Option<ResMut<T>>,
     images: Extract<Res<Assets<Image>>>,
-    mut passes: Local<Vec<Pass>>,
-    mut passes_used: Local<Vec<&'static str>>,
+    frame_count: Extract<Res<FrameCount>>,
+    changed_bindings: Extract<Res<ChangedBindings<T>>>,
+    asset_server: Extract<Res<AssetServer>>,
+    errors: Res<ComputeErrorSender<T>>,
+    status: Res<SharedComputeStatus<T>>,
+    mut pending: ResMut<PendingComputeEvents<T>>,
+    workgroup_sizes: Extract<Res<WorkgroupSizes<T>>>,
+    mut pipeline: ResMut<ComputePipeline<T>>,
+    pipeline_cache: Res<PipelineCache>,
+    settings: Res<ComputeSettings<T>>,
 ) {
-    passes.clear();
-    passes_used.clear();
 
-    // extract main resource
+    // remember changes made on frames without a dispatch, persistent mode needs to know
+    // which bindings have to be uploaded again
+    for binding in changed_bindings.changed.iter() {
+        if !pending.changed_bindings.contains(binding) {
+            pending.changed_bindings.push(*binding);
+        }
+    }
+
+    // hold events until the pipelines are ready, so events sent at Startup aren't lost.
+    // Read before anything else can return, events left in the reader would expire
+    pending.events.extend(
+        compute_events
+            .read_with_id()
+            .map(|(event, id)| (event.clone(), ComputeRequestId::from(id))),
+    );
+    if pending.events.is_empty() {
+        commands.remove_resource::<RenderComputePasses<T>>();
+        return;
+    }
+
+    // extract main resource, the events are held until it's inserted
     let Some(main_resource) = main_resource.as_ref() else {
         warn_once!("no main resource for compute event");
+        commands.remove_resource::<RenderComputePasses<T>>();
         return;
     };
 
-    // check passes are valid
-    let mut passes = compute_events
-        .read()
-        .flat_map(|event| event.passes.iter().cloned())
-        .filter(|pass| {
-            let mut valid = true;
-            if !T::entry_points().contains(&pass.entry) {
-                warn!("invalid entry point for compute event {:?}, skipping", pass);
-                valid = false;
-            }
-            pass.workgroups.iter().for_each(|workgroup| {
-                if workgroup.x == 0 || workgroup.y == 0 || workgroup.z == 0 {
-                    warn!("invalid workgroups for compute event {:?}, skipping", pass);
-                    valid = false;
-                }
-            });
-            valid
+    match status.get() {
+        PipelineStatus::Ready => {}
+        PipelineStatus::Loading => {
+            commands.remove_resource::<RenderComputePasses<T>>();
+            return;
+        }
+        // nothing runs until the shader is fixed, fail them instead of holding on
+        PipelineStatus::Failed(_) => {
+            let requests = pending.events.drain(..).map(|(_, id)| id).collect();
+            errors.report(requests, ComputeError::NotReady);
+            commands.remove_resource::<RenderComputePasses<T>>();
+            return;
+        }
+    }
+
+    // also wait on images still loading from disk, ones that failed to load are reported later
+    let resource = T::extract_resource(main_resource);
+    let pending_passes = || pending.events.iter().flat_map(|(event, _)| event.passes.iter());
+    let sized_images = pending_passes()
+        .filter_map(|pass| match pass.size {
+            Some(DispatchSize::Image(id)) => Some(id),
+            _ => None,
         })
         .collect::<Vec<_>>();
+    let images_loading = T::image_handles(&resource)
+        .iter()
+        .map(|handle| handle.id())
+        .chain(
+            pending
+                .events
+                .iter()
+                .flat_map(|(event, _)| event.textures.iter().map(Handle::id)),
+        )
+        .chain(sized_images)
+        .any(|id| {
+            images.get(id).is_none()
+                && matches!(asset_server.get_load_state(id), Some(LoadState::Loading))
+        });
+    // passes sized from @workgroup_size also wait for the shader to be reflected
+    let sizes_pending = !workgroup_sizes.is_reflected()
+        && pending_passes().any(|pass| pass.size.is_some());
+    if images_loading || sizes_pending {
+        commands.remove_resource::<RenderComputePasses<T>>();
+        return;
+    }
 
-    // remove duplicates events
-    passes.retain(|p| {
-        if !passes_used.contains(&p.entry) {
-            passes_used.push(p.entry);
-            true
-        } else {
-            false
+    // passes with shader defs need a specialized pipeline, queued the first time a combination
+    // is seen and waited on like the others, events whose pipeline failed are dropped
+    let dynamic_defs = resource.dynamic_shader_defs();
+    let mut compiling = false;
+    pending.events.retain(|(event, request)| {
+        for pass in event.passes.iter() {
+            let shader_defs = pass_shader_defs(&dynamic_defs, pass);
+            if shader_defs.is_empty() || !T::entry_points().contains(&pass.entry) {
+                continue;
+            }
+            let Some(id) = pipeline.specialize(&pipeline_cache, pass.entry, &shader_defs) else {
+                continue;
+            };
+            match pipeline_cache.get_compute_pipeline_state(id) {
+                CachedPipelineState::Ok(_) => {}
+                CachedPipelineState::Err(
+                    PipelineCacheError::ShaderNotLoaded(_)
+                    | PipelineCacheError::ShaderImportNotYetAvailable,
+                ) => compiling = true,
+                CachedPipelineState::Err(err) => {
+                    errors.report(
+                        vec![*request],
+                        ComputeError::Pipeline {
+                            entry: pass.entry.to_string(),
+                            message: err.to_string(),
+                        },
+                    );
+                    return false;
+                }
+                _ => compiling = true,
+            }
         }
+        true
     });
+    if compiling || pending.events.is_empty() {
+        commands.remove_resource::<RenderComputePasses<T>>();
+        return;
+    }
+    let mut events = std::mem::take(&mut pending.events);
+
+    // check passes are valid, and keep track of which events they came from
+    let mut requests = Vec::new();
+    let mut passes = Vec::new();
+    let mut readback: Option<Readback> = None;
+    let mut regions = Vec::new();
+    let mut mip_levels = Vec::new();
+    let mut textures: Vec<Handle<Image>> = Vec::new();
+    let mut invalid = Vec::new();
+    let mut superseded = Vec::new();
+    for (event, id) in events.iter() {
+        let valid = event
+            .passes
+            .iter()
+            .filter_map(|pass| size_pass(pass, &workgroup_sizes, &images))
+            .map(|pass| Pass {
+                shader_defs: pass_shader_defs(&dynamic_defs, &pass),
+                ..pass
+            })
+            .filter(|pass| {
+                let valid = pass.is_valid::<T>();
+                if !valid {
+                    warn!(
+                        "invalid entry point, workgroups, iterations or push constants for compute event {:?}, skipping",
+                        pass
+                    );
+                }
+                valid
+            })
+            .collect::<Vec<_>>();
+        // an event without passes only reads back
+        if valid.is_empty() && !event.passes.is_empty() {
+            invalid.push(*id);
+        } else {
+            // the earlier events never run, so they don't complete or read back either
+            if settings.pass_merge == PassMerge::Replace && !valid.is_empty() {
+                superseded.append(&mut requests);
+                passes.clear();
+                readback = None;
+                regions.clear();
+                mip_levels.clear();
+                textures.clear();
+            }
+            requests.push(*id);
+
+            match settings.pass_merge {
+                PassMerge::Replace | PassMerge::Append => passes.extend(valid),
+                PassMerge::Union => {
+                    let earlier = passes.len();
+                    for pass in valid {
+                        if !passes[..earlier].contains(&pass) {
+                            passes.push(pass);
+                        }
+                    }
+                }
+            }
+
+            // only skip staging if every event in this dispatch asked to
+            if !event.no_staging {
+                match readback.as_mut() {
+                    Some(readback) => readback.merge(&event.readback),
+                    None => readback = Some(event.readback.clone()),
+                }
+                regions.push((&event.readback, &event.regions));
+                mip_levels.extend(event.mip_levels.iter());
+                for texture in event.textures.iter() {
+                    if !textures.contains(texture) {
+                        textures.push(texture.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    // let the app world know, so tasks waiting on them resolve
+    if !invalid.is_empty() {
+        errors.report(invalid, ComputeError::InvalidPass);
+    }
+    if !superseded.is_empty() {
+        errors.notify(superseded, ComputeError::Superseded);
+    }
 
     // nothing to do, exit
     // TODO: do we need to remove resource?
-    if passes.is_empty() {
+    if requests.is_empty() {
         //commands.remove_resource::<T>();
         commands.remove_resource::<RenderComputePasses<T>>();
         return;
@@ -221,60 +715,467 @@ pub fn extract_resource<T: ComputeTrait>(
     // extract render world version, and get list of image data
     // TODO: I would love to reuse the image.data, but I dont have access to it here,
     // so creating new vec and sending it back
-    let images_handles = if let Some(mut target_resource) = target_resource {
-        *target_resource = T::extract_resource(main_resource);
-        T::image_handles(&target_resource)
+    let images_handles = T::image_handles(&resource);
+    if let Some(mut target_resource) = target_resource {
+        *target_resource = resource;
     } else {
-        let resource = T::extract_resource(main_resource);
-        let image_handles = T::image_handles(&resource);
-        commands.insert_resource(T::extract_resource(main_resource));
-        image_handles
-    };
+        commands.insert_resource(resource);
+    }
 
     // we need a bit more information about any images while we can still acces them
     // since buffer dimensions can differ
-    let image_info = images_handles
+    let staged_images = images_handles
         .into_iter()
-        .map(|handle| {
-            let image = images.get(&handle).unwrap();
-            let buffer_dimensions = BufferDimensions::new(
-                image.width() as usize,
-                image.height() as usize,
-                image.texture_descriptor.format.pixel_size(),
-            );
-            (handle, buffer_dimensions)
+        .filter(|handle| {
+            readback
+                .as_ref()
+                .is_some_and(|readback| readback.image(handle.id()))
+                && !textures.contains(handle)
+        })
+        .collect::<Vec<_>>();
+    let image_info = staged_images
+        .into_iter()
+        .chain(textures)
+        .filter_map(|handle| {
+            let Some(image) = images.get(&handle) else {
+                errors.report(requests.clone(), ComputeError::MissingImage(handle.id()));
+                return None;
+            };
+            let descriptor = &image.texture_descriptor;
+            if !descriptor.usage.contains(TextureUsages::COPY_SRC)
+                || descriptor.format.block_copy_size(None).is_none()
+            {
+                errors.report(requests.clone(), ComputeError::ImageNotCopyable(handle.id()));
+                return None;
+            }
+
+            // union of the regions every event reading back this image declared,
+            // if any of them didn't declare one the whole image is copied
+            let region = regions
+                .iter()
+                .filter(|(readback, _)| readback.image(handle.id()))
+                .try_fold(None, |region: Option<URect>, (_, event_regions)| {
+                    let (_, event_region) =
+                        event_regions.iter().find(|(id, _)| *id == handle.id())?;
+                    Some(Some(region.map_or(*event_region, |r| r.union(*event_region))))
+                })
+                .flatten()
+                .map(|region| {
+                    // compressed formats can only be copied in whole blocks
+                    let (block_width, block_height) = descriptor.format.block_dimensions();
+                    let block = UVec2::new(block_width, block_height);
+                    let aligned = URect::from_corners(
+                        region.min / block * block,
+                        ((region.max + block - 1) / block) * block,
+                    );
+                    aligned.intersect(URect::new(0, 0, image.width(), image.height()))
+                });
+
+            if region.is_some_and(|region| region.is_empty()) {
+                return None;
+            }
+
+            // mip levels any event asked for, just the first if none did
+            let requested = mip_levels
+                .iter()
+                .filter(|(id, _)| *id == handle.id())
+                .map(|(_, levels)| levels.clone())
+                .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))
+                .unwrap_or(0..1);
+            let levels = requested.start..requested.end.min(descriptor.mip_level_count);
+
+            // every layer of an array texture, or every slice of a 3d texture, is copied
+            let mut offset = 0;
+            let copies = levels
+                .filter_map(|mip_level| {
+                    let size = descriptor.size.mip_level_size(mip_level, descriptor.dimension);
+                    let copy_size = match region {
+                        // regions are in the first mip level
+                        Some(region) if mip_level == 0 => region.size(),
+                        _ => UVec2::new(size.width, size.height),
+                    };
+                    // the format was checked above
+                    let dim = BufferDimensions::for_format(
+                        copy_size.x as usize,
+                        copy_size.y as usize,
+                        size.depth_or_array_layers as usize,
+                        descriptor.format,
+                    )?;
+                    let copy = MipCopy {
+                        mip_level,
+                        region: region.filter(|_| mip_level == 0),
+                        dim,
+                        offset,
+                    };
+                    // buffer offsets need the same alignment as rows
+                    offset += dim.size().next_multiple_of(COPY_BYTES_PER_ROW_ALIGNMENT as usize);
+                    Some(copy)
+                })
+                .collect::<Vec<_>>();
+            if copies.is_empty() {
+                return None;
+            }
+
+            Some(ImageReadback { handle, copies })
         })
         .collect::<Vec<_>>();
 
+    // invalid events were already reported, only the ones dispatched can be held again
+    events.retain(|(_, id)| requests.contains(id));
+
     commands.insert_resource(RenderComputePasses::<T> {
         passes,
+        copied: AtomicBool::new(false),
+        requests,
+        events,
+        submitted_frame: frame_count.0,
+        changed_bindings: std::mem::take(&mut pending.changed_bindings),
+        readback,
         images: image_info,
         _marker: Default::default(),
     });
 }
 
+// Shader defs a pass is compiled with on top of ComputeShader::shader_defs
+fn pass_shader_defs(dynamic_defs: &[ShaderDefVal], pass: &Pass) -> Vec<ShaderDefVal> {
+    dynamic_defs.iter().chain(pass.shader_defs.iter()).cloned().collect()
+}
+
+// Work out the workgroups for a pass made with a DispatchSize
+fn size_pass<T: ComputeTrait>(
+    pass: &Pass,
+    workgroup_sizes: &WorkgroupSizes<T>,
+    images: &Assets<Image>,
+) -> Option<Pass> {
+    let Some(size) = pass.size else {
+        return Some(pass.clone());
+    };
+    let invocations = match size {
+        DispatchSize::Invocations(invocations) => invocations,
+        DispatchSize::Image(id) => {
+            let Some(image) = images.get(id) else {
+                warn!("image for compute pass {:?} isn't loaded, skipping", pass);
+                return None;
+            };
+            let size = image.texture_descriptor.size;
+            UVec3::new(size.width, size.height, size.depth_or_array_layers)
+        }
+    };
+    let Some(workgroups) = workgroup_sizes.workgroups_for(pass.entry, invocations) else {
+        warn!("no @workgroup_size found for compute pass {:?}, skipping", pass);
+        return None;
+    };
+    Some(Pass {
+        workgroups: vec![workgroups],
+        size: None,
+        ..pass.clone()
+    })
+}
+
 fn prepare_bind_group<T: ComputeTrait>(
     mut commands: Commands,
     pipeline: Res<ComputePipeline<T>>,
     gpu_images: Res<RenderAssets<Image>>,
     fallback_image: Res<FallbackImage>,
-    data: Res<T>,
+    mut data: ResMut<T>,
     render_device: Res<RenderDevice>,
-    render_compute_passes: Res<RenderComputePasses<T>>,
+    render_queue: Res<RenderQueue>,
+    mut render_compute_passes: ResMut<RenderComputePasses<T>>,
+    settings: Res<ComputeSettings<T>>,
+    pool: Res<StagingBufferPool<T>>,
+    errors: Res<ComputeErrorSender<T>>,
+    mut pending: ResMut<PendingComputeEvents<T>>,
+    previous: Option<ResMut<PreparedCompute<T>>>,
+    mut staged_bindings: Local<Option<Vec<u32>>>,
 ) {
-    // Generate normal bind group
-    let Ok(prepared) = data.as_bind_group(
-        &pipeline.bind_group_layout,
-        &render_device,
-        &gpu_images,
-        &fallback_image,
-    ) else {
-        error!("error preparing bind group for compute event");
-        return;
+    // the gpu textures for our images, if an image asset is modified it gets a new texture
+    // and any bind group we kept would be pointing at the old one
+    let image_textures = T::image_handles(&data)
+        .iter()
+        .filter_map(|handle| gpu_images.get(handle).map(|image| image.texture.id()))
+        .collect::<Vec<_>>();
+
+    // buffers staged fields are copied from, kept between rebuilds to write into again
+    let mut field_buffers = previous
+        .as_ref()
+        .map(|previous| previous.field_buffers.clone())
+        .unwrap_or_default();
+    let mut indirect_buffer = previous
+        .as_ref()
+        .and_then(|previous| previous.indirect_buffer.clone());
+
+    // persistent mode keeps the bindings from the last dispatch, unless the app world changed one
+    let mut previous = previous.filter(|_| settings.persistent);
+    // after an odd number of iterations the latest state is in the second binding of each
+    // ping pong pair, swap them back so the next dispatch starts from it
+    if let Some(previous) = previous.as_mut().filter(|previous| previous.ping_pong_odd) {
+        previous.swap_ping_pong();
+    }
+    let reuse = render_compute_passes.changed_bindings.is_empty()
+        && previous
+            .as_ref()
+            .is_some_and(|previous| previous.image_textures == image_textures);
+
+    let rebuilt = if reuse {
+        None
+    } else {
+        // Generate normal bind group
+        let prepared = match data.as_bind_group(
+            &pipeline.bind_group_layout,
+            &render_device,
+            &gpu_images,
+            &fallback_image,
+        ) {
+            Ok(prepared) => prepared,
+            // an image isn't on the gpu yet, hold the events and try again next frame
+            Err(AsBindGroupError::RetryNextUpdate) => {
+                pending.retry(&mut render_compute_passes);
+                commands.remove_resource::<RenderComputePasses<T>>();
+                return;
+            }
+            Err(err) => {
+                errors.report(
+                    render_compute_passes.requests.clone(),
+                    ComputeError::BindGroup(format!("{:?}", err)),
+                );
+                // dont run or read back the previous frames buffers
+                commands.remove_resource::<RenderComputePasses<T>>();
+                return;
+            }
+        };
+
+        let mut bindings = prepared.bindings;
+        let mut replaced = false;
+
+        // keep the gpu side contents of persistent bindings and of buffers that didn't change
+        if let Some(previous) = previous.as_mut() {
+            for (index, resource) in bindings.iter_mut() {
+                // textures come from the image assets, not T, so only buffers are kept
+                let unchanged = matches!(resource, OwnedBindingResource::Buffer(_))
+                    && !render_compute_passes.changed_bindings.contains(index);
+                if !unchanged && !T::persistent_bindings().contains(index) {
+                    continue;
+                }
+                if let Some((_, previous_resource)) =
+                    previous.bindings.iter_mut().find(|(i, _)| i == index)
+                {
+                    std::mem::swap(resource, previous_resource);
+                    replaced = true;
+                }
+            }
+        }
+
+        // AsBindGroup only gives `staging` buffers COPY_SRC, staged fields are copied from a
+        // buffer of our own, written again each time the field is uploaded
+        for (index, resource) in bindings.iter_mut() {
+            let OwnedBindingResource::Buffer(buffer) = resource else {
+                continue;
+            };
+            if buffer.usage().contains(BufferUsages::COPY_SRC) {
+                continue;
+            }
+            let Some(field) = data.staged_field(*index) else {
+                continue;
+            };
+            let field_buffer = match field_buffers
+                .iter()
+                .find(|(i, field_buffer)| i == index && field_buffer.size() == buffer.size())
+            {
+                Some((_, field_buffer)) => field_buffer.clone(),
+                None => {
+                    let field_buffer = render_device.create_buffer(&BufferDescriptor {
+                        label: T::label(),
+                        size: buffer.size(),
+                        usage: buffer.usage() | BufferUsages::COPY_SRC,
+                        mapped_at_creation: false,
+                    });
+                    field_buffers.retain(|(i, _)| i != index);
+                    field_buffers.push((*index, field_buffer.clone()));
+                    field_buffer
+                }
+            };
+            render_queue.write_buffer(&field_buffer, 0, &field.write());
+            *resource = OwnedBindingResource::Buffer(field_buffer);
+            replaced = true;
+        }
+
+        let bind_group = if replaced {
+            let entries = bindings
+                .iter()
+                .map(|(index, resource)| BindGroupEntry {
+                    binding: *index,
+                    resource: resource.get_binding(),
+                })
+                .collect::<Vec<_>>();
+            render_device.create_bind_group(T::label(), &pipeline.bind_group_layout, &entries)
+        } else {
+            prepared.bind_group
+        };
+        Some((bindings, bind_group))
     };
 
-    // get staging buffers, without images
-    let staging_buffers = data.create_staging_buffers(&render_device);
+    // the same bindings with each ping pong pair swapped, for odd iterations
+    let swapped_bind_group = match &rebuilt {
+        Some((bindings, _)) if !T::ping_pong_bindings().is_empty() => {
+            let mismatched = T::ping_pong_bindings().iter().find(|(a, b)| {
+                let size = |index| match bindings.iter().find(|(i, _)| i == index) {
+                    Some((_, OwnedBindingResource::Buffer(buffer))) => Some(buffer.size()),
+                    _ => None,
+                };
+                size(a).is_none() || size(a) != size(b)
+            });
+            if let Some((a, b)) = mismatched {
+                errors.report(
+                    render_compute_passes.requests.clone(),
+                    ComputeError::BindGroup(format!(
+                        "ping pong bindings {} and {} need to be buffers of the same size",
+                        a, b
+                    )),
+                );
+                commands.remove_resource::<RenderComputePasses<T>>();
+                return;
+            }
+            let entries = bindings
+                .iter()
+                .map(|(index, resource)| BindGroupEntry {
+                    binding: ping_pong_partner::<T>(*index).unwrap_or(*index),
+                    resource: resource.get_binding(),
+                })
+                .collect::<Vec<_>>();
+            Some(render_device.create_bind_group(
+                T::label(),
+                &pipeline.bind_group_layout,
+                &entries,
+            ))
+        }
+        _ => None,
+    };
+
+    let bindings = match &rebuilt {
+        Some((bindings, _)) => bindings,
+        None => match previous.as_ref() {
+            Some(previous) => &previous.bindings,
+            None => return,
+        },
+    };
+
+    // indirect passes copy their arguments out of the binding, so it has to be a buffer with
+    // COPY_SRC and room for them after the offset
+    let args_size = std::mem::size_of::<DispatchIndirectArgs>() as u64;
+    let invalid_indirect = render_compute_passes
+        .passes
+        .iter()
+        .filter_map(|pass| pass.indirect)
+        .find(|indirect| {
+            !bindings.iter().any(|(index, resource)| {
+                *index == indirect.binding
+                    && matches!(resource, OwnedBindingResource::Buffer(buffer)
+                        if buffer.usage().contains(BufferUsages::COPY_SRC)
+                            && indirect.offset + args_size <= buffer.size())
+            })
+        });
+    if let Some(indirect) = invalid_indirect {
+        errors.report(
+            render_compute_passes.requests.clone(),
+            ComputeError::InvalidIndirect(indirect.binding),
+        );
+        // keep the bindings we made, but dont run or read back this dispatch
+        render_compute_passes.readback = None;
+        render_compute_passes.images.clear();
+        commands.remove_resource::<RenderComputePasses<T>>();
+    }
+
+    // a binding can't be STORAGE read_write and INDIRECT in the same dispatch, so the node
+    // copies the arguments into this buffer before each indirect pass
+    if indirect_buffer.is_none()
+        && render_compute_passes
+            .passes
+            .iter()
+            .any(|pass| pass.indirect.is_some())
+    {
+        indirect_buffer = Some(render_device.create_buffer(&BufferDescriptor {
+            label: T::label(),
+            size: args_size,
+            usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        }));
+    }
+
+    // which storage bindings are marked staging doesn't change, so only ask once
+    let staged_bindings = staged_bindings.get_or_insert_with(|| {
+        let mut staged = data
+            .create_staging_buffers(&render_device)
+            .storage
+            .iter()
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>();
+        for (index, _) in bindings.iter() {
+            let copied_back =
+                T::staged_buffers().contains(index) || data.staged_field(*index).is_some();
+            if copied_back && !staged.contains(index) {
+                staged.push(*index);
+            }
+        }
+        staged
+    });
+
+    // get staging buffers, without images, only for the bindings this dispatch reads back
+    // sized to match the buffers we are copying from
+    let mut not_copyable = Vec::new();
+    let mut staging_buffers = match &render_compute_passes.readback {
+        Some(readback) => staged_bindings
+            .iter()
+            .filter(|index| readback.binding(**index))
+            .filter_map(|index| match bindings.iter().find(|(i, _)| i == index) {
+                Some((_, OwnedBindingResource::Buffer(gpu_buffer)))
+                    if !gpu_buffer.usage().contains(BufferUsages::COPY_SRC) =>
+                {
+                    not_copyable.push(*index);
+                    None
+                }
+                Some((_, OwnedBindingResource::Buffer(gpu_buffer))) => Some((
+                    *index,
+                    pool.acquire(
+                        &render_device,
+                        gpu_buffer.size(),
+                        BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+                    ),
+                )),
+                _ => {
+                    error!("failed to find binding resource for staging");
+                    None
+                }
+            })
+            .collect::<Vec<_>>(),
+        None => Vec::new(),
+    };
+
+    // `#[buffer]` bindings are made by the user, copying one without COPY_SRC fails validation
+    if !not_copyable.is_empty() {
+        errors.report(
+            render_compute_passes.requests.clone(),
+            ComputeError::BindGroup(format!(
+                "staged bindings {:?} can't be copied, their buffers weren't created with COPY_SRC",
+                not_copyable
+            )),
+        );
+        for (_, buffer) in staging_buffers.drain(..) {
+            pool.release(buffer);
+        }
+        // keep the bindings we made, but dont run or read back this dispatch
+        render_compute_passes.images.clear();
+        commands.remove_resource::<RenderComputePasses<T>>();
+    }
+
+    // images to read back have to be on the gpu, the node copies from their textures
+    let requests = render_compute_passes.requests.clone();
+    render_compute_passes.images.retain(|image| {
+        let loaded = gpu_images.get(&image.handle).is_some();
+        if !loaded {
+            errors.report(requests.clone(), ComputeError::MissingImage(image.handle.id()));
+        }
+        loaded
+    });
 
     // create staging buffers for images
     // NOTE: It is a WebGPU requirement that ImageCopyBuffer.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
@@ -284,134 +1185,477 @@ fn prepare_bind_group<T: ComputeTrait>(
     let staging_image_buffers = render_compute_passes
         .images
         .iter()
-        .map(|(_handle, dim)| {
-            render_device.create_buffer(&BufferDescriptor {
-                label: None,
-                usage: BufferUsages::COPY_DST | BufferUsages::MAP_READ,
-                size: (dim.padded_bytes_per_row * dim.height) as u64,
-                mapped_at_creation: false,
-            })
+        .map(|image| {
+            pool.acquire(
+                &render_device,
+                image.size() as u64,
+                BufferUsages::COPY_DST | BufferUsages::MAP_READ,
+            )
         })
         .collect::<Vec<_>>();
 
-    commands.insert_resource(PreparedCompute::<T> {
-        bindings: prepared.bindings,
-        bind_group: prepared.bind_group,
-        staging_image_buffers: staging_image_buffers,
-        staging_buffers,
-        _marker: Default::default(),
-    });
+    // every iteration of every pass alternates the ping pong pairs
+    let ping_pong_odd = !T::ping_pong_bindings().is_empty()
+        && render_compute_passes
+            .passes
+            .iter()
+            .map(|pass| pass.iterations)
+            .sum::<u32>()
+            % 2
+            == 1;
+
+    match (rebuilt, previous) {
+        (Some((bindings, bind_group)), _) => {
+            commands.insert_resource(PreparedCompute::<T> {
+                bindings,
+                bind_group,
+                swapped_bind_group,
+                ping_pong_odd,
+                image_textures,
+                field_buffers,
+                indirect_buffer,
+                staging_image_buffers,
+                staging_buffers,
+                _marker: Default::default(),
+            });
+        }
+        (None, Some(mut previous)) => {
+            previous.indirect_buffer = indirect_buffer;
+            previous.ping_pong_odd = ping_pong_odd;
+            previous.staging_buffers = staging_buffers;
+            previous.staging_image_buffers = staging_image_buffers;
+        }
+        (None, None) => {}
+    }
 }
 
-// This reads the staging buffers and sends the data to the app world
-fn read_and_send<T: ComputeTrait>(
+// Starts mapping the staging buffers for this frames dispatch, the gpu hasn't finished with
+// them yet so we dont wait here, see poll_readbacks
+fn start_readback<T: ComputeTrait>(
     mut commands: Commands,
-    mut data: ResMut<T>,
     prepared: Res<PreparedCompute<T>>,
-    render_compute_passes: ResMut<RenderComputePasses<T>>,
-    sender: Res<ComputeSender<T>>,
-    render_device: Res<RenderDevice>,
-) {      
-    // create buffer slices for storage buffers and images
-    let storage_buffer_slices = prepared
+    render_compute_passes: Res<RenderComputePasses<T>>,
+    mut readbacks: ResMut<ComputeReadbacks<T>>,
+    pool: Res<StagingBufferPool<T>>,
+    errors: Res<ComputeErrorSender<T>>,
+) {
+    // the node didn't get to record the copies, the passes didn't all run and the staging
+    // buffers hold nothing from this dispatch, so hand them straight back to the pool
+    if !render_compute_passes.copied.load(Ordering::Acquire) {
+        prepared
+            .staging_buffers
+            .iter()
+            .map(|(_, buffer)| buffer)
+            .chain(prepared.staging_image_buffers.iter())
+            .for_each(|buffer| pool.release(buffer.clone()));
+        errors.report(render_compute_passes.requests.clone(), ComputeError::NotReady);
+        commands.remove_resource::<RenderComputePasses<T>>();
+        return;
+    }
+
+    let storage = prepared
         .staging_buffers
-        .storage
         .iter()
-        .map(|(index, buffer)| {
-            let buffer_slice = buffer.slice(..);
-            buffer_slice.map_async(MapMode::Read, move |result| {
-                let err = result.err();
-                if err.is_some() {
-                    let some_err = err.unwrap();
-                    panic!("{}", some_err.to_string());
-                }
-            });
-            (*index, buffer_slice)
-        })
+        .map(|(index, buffer)| (*index, buffer.clone()))
         .collect::<Vec<_>>();
 
-    let image_buffer_slices = prepared
-        .staging_image_buffers
+    let images = render_compute_passes
+        .images
         .iter()
-        .map(|buffer| {
-            let buffer_slice = buffer.slice(..);
-            buffer_slice.map_async(MapMode::Read, move |result| {
-                let err = result.err();
-                if err.is_some() {
-                    let some_err = err.unwrap();
-                    panic!("{}", some_err.to_string());
-                }
-            });
-            buffer_slice
-        })
+        .zip(prepared.staging_image_buffers.iter())
+        .map(|(image, buffer)| (image.clone(), buffer.clone()))
         .collect::<Vec<_>>();
 
-    // wait for gpu to finish
-    render_device.wgpu_device().poll(Maintain::Wait);
+    let remaining = Arc::new(AtomicUsize::new(storage.len() + images.len()));
+    let failed = Arc::new(AtomicBool::new(false));
 
-    // Write the data from buffer slices back to T
-    data.bypass_change_detection()
-        .map_storage_mappings(&storage_buffer_slices);
-
-    let image_data = render_compute_passes
-        .images
-        .iter() // Use .iter() to avoid moving/consuming `images`
-        .enumerate()
-        .map(|(index, (handle, dim))| {
-            // Clone handle and dim if necessary; assume slice can be copied or cloned as needed
-            let padded_data = &image_buffer_slices[index].get_mapped_range();
-
-            // coverted form padded buffer,
-            // TODO was reusing image.data, but dont have access to it here
-            let mut image_data = Vec::new();
-            for row in 0..dim.height {
-                let start = row * dim.padded_bytes_per_row;
-                let end = start + dim.unpadded_bytes_per_row;
-                image_data.extend_from_slice(&padded_data[start..end]);
+    let buffers = storage
+        .iter()
+        .map(|(_, buffer)| buffer)
+        .chain(images.iter().map(|(_, buffer)| buffer));
+    for buffer in buffers {
+        let remaining = remaining.clone();
+        let failed = failed.clone();
+        buffer.slice(..).map_async(MapMode::Read, move |result| {
+            // reported from poll_readbacks, where we know which requests it belongs to
+            if result.is_err() {
+                failed.store(true, Ordering::Release);
             }
+            remaining.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
 
-            (handle.clone_weak(), image_data)
-        })
-        .collect::<Vec<_>>();
+    readbacks.pending.push_back(PendingReadback {
+        requests: render_compute_passes.requests.clone(),
+        submitted_frame: render_compute_passes.submitted_frame,
+        passes: render_compute_passes.passes.clone(),
+        dont_copy: render_compute_passes.readback.is_none(),
+        storage,
+        images,
+        remaining,
+        failed,
+        _marker: PhantomData,
+    });
 
-    if let Err(error) = sender.try_send(ComputeMessage::<T> {
-        data: if storage_buffer_slices.len() == 0 {
-            debug!("no data to send");
-            None
+    commands.remove_resource::<RenderComputePasses<T>>();
+}
+
+// Polls the gpu without blocking, and sends any readbacks that have finished mapping
+// to the app world, ComputeComplete will arrive a few frames after the dispatch
+fn poll_readbacks<T: ComputeTrait>(
+    mut readbacks: ResMut<ComputeReadbacks<T>>,
+    settings: Res<ComputeSettings<T>>,
+    pool: Res<StagingBufferPool<T>>,
+    sender: Res<ComputeSender<T>>,
+    errors: Res<ComputeErrorSender<T>>,
+    render_device: Res<RenderDevice>,
+) {
+    if !readbacks.pending.is_empty() {
+        // runs any map_async callbacks that are ready, if we have more dispatches in flight
+        // than allowed, wait on the gpu instead so we dont keep piling up staging buffers
+        if readbacks.pending.len() > settings.frames_in_flight {
+            debug!("too many compute dispatches in flight, waiting on gpu");
+            render_device.wgpu_device().poll(Maintain::Wait);
         } else {
-            debug!("no data to send");
-            Some(data.clone())
-        },
-        images: image_data,
-    }) {
-        match error {
-            crossbeam_channel::TrySendError::Full(_) => todo!(),
-            crossbeam_channel::TrySendError::Disconnected(_) => todo!(),
-            // bevy_time::TrySendError::Full(_) => {
-            //     panic!("The TimeSender channel should always be empty during render. You might need to add the bevy::core::time_system to your app.",);
-            // }
-            // bevy_time::TrySendError::Disconnected(_) => {
-            //     // ignore disconnected errors, the main world probably just got dropped during shutdown
-            // }
+            render_device.wgpu_device().poll(Maintain::Poll);
         }
     }
 
-    commands.remove_resource::<RenderComputePasses<T>>();
+    // readbacks are sent in order, so a finished one waits on any before it
+    while readbacks
+        .pending
+        .front()
+        .is_some_and(|readback| readback.is_ready())
+    {
+        let Some(readback) = readbacks.pending.pop_front() else {
+            break;
+        };
+
+        if readback.has_failed() {
+            // some buffers may still be mapped, drop them instead of returning them to the pool
+            errors.report(readback.requests, ComputeError::MapFailed);
+            continue;
+        }
+
+        let image_data = readback
+            .images
+            .iter()
+            .map(|(ImageReadback { handle, copies }, buffer)| {
+                let padded_data = buffer.slice(..).get_mapped_range();
+
+                // coverted form padded buffer, one per mip level
+                // TODO was reusing image.data, but dont have access to it here
+                let mips = copies
+                    .iter()
+                    .map(|MipCopy { mip_level, region, dim, offset }| {
+                        let mut data = Vec::with_capacity(dim.unpadded_bytes_per_row * dim.rows());
+                        for row in 0..dim.rows() {
+                            let start = offset + row * dim.padded_bytes_per_row;
+                            let end = start + dim.unpadded_bytes_per_row;
+                            data.extend_from_slice(&padded_data[start..end]);
+                        }
+                        ImageData {
+                            mip_level: *mip_level,
+                            region: *region,
+                            data,
+                        }
+                    })
+                    .collect::<Vec<_>>();
+
+                (handle.clone_weak(), mips)
+            })
+            .collect::<Vec<_>>();
 
-    // TODO: havent noticed any issues without explicitly removing these
-    // need to check this
-    // drop(storage_buffer_slices);
-    // drop(image_buffer_slices);
+        // all mapped image views are dropped, safe to unmap and reuse
+        for (_, buffer) in readback.images {
+            buffer.unmap();
+            pool.release(buffer);
+        }
 
-    //         storage_buffers.iter().for_each(|(_, buffer)| {
-    //             buffer.unmap();
-    //         });
-    //         stage_image.iter().for_each(|(_, buffer, _)| {
-    //             buffer.unmap();
-    //         });
+        // storage buffers are sent still mapped, listen_receiver maps them straight into
+        // the app world resource so only the staged fields are written
+        let message = ComputeMessage::<T> {
+            requests: readback.requests,
+            submitted_frame: readback.submitted_frame,
+            passes: readback.passes,
+            dont_copy: readback.dont_copy,
+            storage: readback.storage,
+            images: image_data,
+            _marker: PhantomData,
+        };
+        send_message(message, &sender, settings.channel_policy, &pool, &errors);
+    }
+}
 
-    //         // notify complete
-    //         complete_events.send(ComputeComplete::<T>::default());
+// Sends a result to the app world, if the channel is full the policy decides what is dropped
+fn send_message<T: ComputeTrait>(
+    mut message: ComputeMessage<T>,
+    sender: &ComputeSender<T>,
+    policy: ComputeChannelPolicy,
+    pool: &StagingBufferPool<T>,
+    errors: &ComputeErrorSender<T>,
+) {
+    loop {
+        match sender.try_send(message) {
+            Ok(()) => return,
+            Err(crossbeam_channel::TrySendError::Disconnected(message)) => {
+                // the app world is gone, most likely the app is exiting
+                debug!("compute channel disconnected, dropping result");
+                message.recycle(pool);
+                return;
+            }
+            Err(crossbeam_channel::TrySendError::Full(full)) => {
+                message = full;
+                match policy {
+                    ComputeChannelPolicy::DropOldest => {
+                        // if the app world took it first there is room now anyway
+                        let oldest = sender.waiting.as_ref().and_then(|r| r.try_recv().ok());
+                        if let Some(mut oldest) = oldest {
+                            // sent on the unbounded error channel right away, waiting for
+                            // the next result could mean never hearing about them
+                            errors.notify(
+                                std::mem::take(&mut oldest.requests),
+                                ComputeError::Dropped,
+                            );
+                            oldest.recycle(pool);
+                        }
+                    }
+                    ComputeChannelPolicy::Coalesce => {
+                        let waiting = sender
+                            .waiting
+                            .as_ref()
+                            .map(|r| r.try_iter().collect::<Vec<_>>())
+                            .unwrap_or_default();
+                        // newest first, each older one goes in front of the requests so far
+                        for older in waiting.into_iter().rev() {
+                            message.coalesce(older, pool);
+                        }
+                    }
+                    // unbounded channels are never full
+                    ComputeChannelPolicy::DropNewest | ComputeChannelPolicy::Unbounded => {
+                        errors.notify(
+                            std::mem::take(&mut message.requests),
+                            ComputeError::Dropped,
+                        );
+                        message.recycle(pool);
+                        return;
+                    }
+                }
+            }
+        }
+    }
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+    use bevy::{
+        app::PluginsState,
+        ecs::event::ManualEventReader,
+        render::{
+            extract_resource::ExtractResource,
+            render_graph::RenderLabel,
+            render_resource::{AsBindGroup, PushConstantRange, ShaderRef, ShaderStages},
+        },
+        window::ExitCondition,
+        winit::WinitPlugin,
+    };
+
+    use super::*;
+
+    /// Compute resource for tests, nothing here needs a gpu
+    #[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug, Default)]
+    pub(crate) struct Simple {
+        #[uniform(0)]
+        uni: f32,
+    }
+
+    const PUSH_CONSTANT_RANGES: &[PushConstantRange] = &[PushConstantRange {
+        stages: ShaderStages::COMPUTE,
+        range: 0..16,
+    }];
+
+    impl ComputeShader for Simple {
+        fn shader() -> ShaderRef {
+            ShaderRef::Default
+        }
+
+        fn push_constant_ranges<'a>() -> &'a [PushConstantRange] {
+            PUSH_CONSTANT_RANGES
+        }
+
+        fn set_nodes(_render_graph: &mut RenderGraph) {}
+    }
+
+    /// A result with nothing staged, like a dispatch sent with no_staging
+    pub(crate) fn message(request: usize) -> ComputeMessage<Simple> {
+        ComputeMessage {
+            requests: vec![ComputeRequestId(request)],
+            submitted_frame: request as u32,
+            passes: vec![Pass::new("main", UVec3::ONE)],
+            dont_copy: true,
+            storage: Vec::new(),
+            images: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    fn requests(ids: impl IntoIterator<Item = usize>) -> Vec<ComputeRequestId> {
+        ids.into_iter().map(ComputeRequestId).collect()
+    }
+
+    // just the app world side of ComputePlugin, the render world is played by the test
+    fn app_world(plugin: &ComputePlugin<Simple>) -> (App, ComputeSender<Simple>) {
+        let (sender, receiver) =
+            create_compute_channels::<Simple>(plugin.frames_in_flight, plugin.channel_policy);
+        let mut app = App::new();
+        app.insert_resource(receiver)
+            .insert_resource(plugin.settings())
+            .insert_resource(StagingBufferPool::<Simple>::new(2))
+            .init_resource::<Simple>()
+            .init_resource::<Assets<Image>>()
+            .init_resource::<ComputeWaiters<Simple>>()
+            .init_resource::<ChangedBindings<Simple>>()
+            .init_resource::<FrameCount>()
+            .add_event::<ComputeComplete<Simple>>()
+            .add_event::<ComputeFailed<Simple>>()
+            .add_event::<AssetEvent<Image>>()
+            .add_systems(Last, listen_receiver::<Simple>);
+        (app, sender)
+    }
+
+    #[test]
+    fn every_result_completes_once_in_order() {
+        let (mut app, sender) = app_world(&ComputePlugin::default());
+        let mut reader = ManualEventReader::<ComputeComplete<Simple>>::default();
+        let mut completed = Vec::new();
+
+        // with pipelined rendering several dispatches can finish before Last runs
+        for batch in [0..5, 5..6, 6..6, 6..9] {
+            for request in batch {
+                sender.send(message(request)).unwrap();
+            }
+            app.update();
+            let events = app.world.resource::<Events<ComputeComplete<Simple>>>();
+            completed.extend(reader.read(events).flat_map(|complete| complete.requests.clone()));
+        }
+
+        assert_eq!(completed, requests(0..9));
+    }
+
+    fn send_three(
+        policy: ComputeChannelPolicy,
+    ) -> (ComputeReceiver<Simple>, ComputeErrorReceiver<Simple>) {
+        // one frame in flight, room for two results
+        let (sender, receiver) = create_compute_channels::<Simple>(1, policy);
+        let (errors, error_receiver) = create_error_channels::<Simple>();
+        let pool = StagingBufferPool::<Simple>::new(2);
+        for request in 0..3 {
+            send_message(message(request), &sender, policy, &pool, &errors);
+        }
+        (receiver, error_receiver)
+    }
+
+    fn received(receiver: &ComputeReceiver<Simple>) -> Vec<Vec<ComputeRequestId>> {
+        receiver.try_iter().map(|message| message.requests).collect()
+    }
+
+    #[test]
+    fn drop_newest_reports_the_dropped_result() {
+        let (receiver, errors) = send_three(ComputeChannelPolicy::DropNewest);
+        assert_eq!(received(&receiver), vec![requests([0]), requests([1])]);
+
+        let failed = errors.try_recv().unwrap();
+        assert_eq!(failed.requests, requests([2]));
+        assert_eq!(failed.error, ComputeError::Dropped);
+        assert!(errors.try_recv().is_err());
+    }
+
+    #[test]
+    fn drop_oldest_reports_the_dropped_result() {
+        let (receiver, errors) = send_three(ComputeChannelPolicy::DropOldest);
+        assert_eq!(received(&receiver), vec![requests([1]), requests([2])]);
 
+        let failed = errors.try_recv().unwrap();
+        assert_eq!(failed.requests, requests([0]));
+        assert_eq!(failed.error, ComputeError::Dropped);
+    }
+
+    #[test]
+    fn coalesce_keeps_every_request_in_order() {
+        let (receiver, errors) = send_three(ComputeChannelPolicy::Coalesce);
+        assert_eq!(received(&receiver), vec![requests(0..3)]);
+        assert!(errors.try_recv().is_err());
+    }
+
+    /// Same as the basic example, the shader adds uni to every element
+    #[derive(AsBindGroup, ExtractResource, Resource, Clone, Debug)]
+    struct Basic {
+        #[uniform(0)]
+        uni: f32,
+        #[storage(1, visibility(all), staging)]
+        vec: Vec<f32>,
+    }
+
+    #[derive(Debug, Hash, PartialEq, Eq, Clone, RenderLabel)]
+    struct BasicLabel;
+
+    impl ComputeShader for Basic {
+        fn shader() -> ShaderRef {
+            "basic.wgsl".into()
+        }
+
+        fn set_nodes(render_graph: &mut RenderGraph) {
+            render_graph.add_node(BasicLabel, ComputeNode::<Basic>::default());
+            render_graph.add_node_edge(BasicLabel, bevy::render::graph::CameraDriverLabel);
+        }
+    }
+
+    // The whole plugin on a real device, DefaultPlugins brings PipelinedRenderingPlugin so the
+    // render world runs on its own thread like in an app, just without a window
+    #[test]
+    #[ignore = "needs a gpu"]
+    fn reads_back_with_pipelined_rendering() {
+        let mut app = App::new();
+        app.add_plugins((
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .disable::<WinitPlugin>(),
+            ComputePlugin::<Basic>::default(),
+        ))
+        .insert_resource(Basic {
+            uni: 1.0,
+            vec: vec![1.0, 2.0, 3.0, 4.0],
+        });
+
+        // there is no runner to finish the plugins, the renderer is created in the background
+        while app.plugins_state() != PluginsState::Ready {
+            bevy::tasks::tick_global_task_pools_on_main_thread();
+        }
+        app.finish();
+        app.cleanup();
+
+        // sent before the pipeline has loaded, the event is held until it has
+        app.world.send_event(ComputeEvent::<Basic>::new_xyz(4, 1, 1));
+        let mut completed = ManualEventReader::<ComputeComplete<Basic>>::default();
+        let mut failed = ManualEventReader::<ComputeFailed<Basic>>::default();
+        for _ in 0..1000 {
+            app.update();
+
+            let events = app.world.resource::<Events<ComputeFailed<Basic>>>();
+            if let Some(event) = failed.read(events).next() {
+                panic!("compute failed: {}", event.error);
+            }
+
+            let events = app.world.resource::<Events<ComputeComplete<Basic>>>();
+            if completed.read(events).next().is_some() {
+                assert_eq!(app.world.resource::<Basic>().vec, vec![2.0, 3.0, 4.0, 5.0]);
+                return;
+            }
+        }
+        panic!("compute never completed");
+    }
+}
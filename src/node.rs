@@ -1,4 +1,4 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::atomic::Ordering};
 
 use bevy::{
     prelude::*,
@@ -6,14 +6,18 @@ use bevy::{
         render_asset::RenderAssets,
         render_graph::{self},
         render_resource::{
-            CachedPipelineState, ComputePassDescriptor, Extent3d, ImageCopyBuffer, ImageDataLayout,
-            OwnedBindingResource, PipelineCache,
+            CachedPipelineState, ComputePassDescriptor, Extent3d, ImageCopyBuffer, ImageCopyTexture,
+            ImageDataLayout, Origin3d, OwnedBindingResource, PipelineCache, PipelineCacheError,
+            TextureAspect,
         },
         renderer::RenderContext,
     },
 };
 
-use crate::{ComputePipeline, ComputeTrait, PreparedCompute, RenderComputePasses};
+use crate::{
+    channel::ComputeErrorSender, ComputeError, ComputePipeline, ComputeTrait, PipelineStatus,
+    PreparedCompute, RenderComputePasses, SharedComputeStatus,
+};
 
 // #[derive(Debug, Hash, Clone, RenderLabel)]
 // pub struct ComputeLabel<T: ComputeTrait>(PhantomData<T>);
@@ -24,20 +28,15 @@ use crate::{ComputePipeline, ComputeTrait, PreparedCompute, RenderComputePasses}
 //     }
 // }
 
-enum ComputeState {
-    Loading,
-    Ready,
-}
-
 pub struct ComputeNode<T: ComputeTrait> {
-    state: ComputeState,
+    status: PipelineStatus,
     _marker: PhantomData<T>,
 }
 
 impl<T: ComputeTrait> Default for ComputeNode<T> {
     fn default() -> Self {
         Self {
-            state: ComputeState::Loading,
+            status: PipelineStatus::Loading,
             _marker: Default::default(),
         }
     }
@@ -48,24 +47,41 @@ impl<T: ComputeTrait> render_graph::Node for ComputeNode<T> {
         let pipeline = world.resource::<ComputePipeline<T>>();
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        // if the corresponding pipeline has loaded, transition to the next stage
-        match self.state {
-            ComputeState::Loading => {
-                // if all pipelines are ready, transition to the next stage
-                if pipeline.pipelines.iter().all(|p| {
-                    if let CachedPipelineState::Ok(_) =
-                        pipeline_cache.get_compute_pipeline_state(*p)
-                    {
-                        true
-                    } else {
-                        false
-                    }
-                }) {
-                    self.state = ComputeState::Ready;
+        // checked every frame, a hot reloaded shader can fail or be fixed at any time
+        let mut failed = None;
+        let mut status = PipelineStatus::Ready;
+        for (entry, id) in T::entry_points().iter().zip(pipeline.pipelines.iter()) {
+            match pipeline_cache.get_compute_pipeline_state(*id) {
+                CachedPipelineState::Ok(_) => {}
+                // the pipeline cache retries these once the shader loads
+                CachedPipelineState::Err(
+                    PipelineCacheError::ShaderNotLoaded(_)
+                    | PipelineCacheError::ShaderImportNotYetAvailable,
+                ) => status = PipelineStatus::Loading,
+                CachedPipelineState::Err(err) => {
+                    let message = err.to_string();
+                    status = PipelineStatus::Failed(format!("{}: {}", entry, message));
+                    failed = Some((entry.to_string(), message));
+                    break;
                 }
+                _ => status = PipelineStatus::Loading,
             }
-            ComputeState::Ready => {}
         }
+        // no shader, already reported when the pipelines were created
+        if pipeline.pipelines.is_empty() {
+            status = PipelineStatus::Failed("no shader".to_string());
+        }
+
+        if status == self.status {
+            return;
+        }
+        if let Some((entry, message)) = failed {
+            world
+                .resource::<ComputeErrorSender<T>>()
+                .report(Vec::new(), ComputeError::Pipeline { entry, message });
+        }
+        world.resource::<SharedComputeStatus<T>>().set(status.clone());
+        self.status = status;
     }
 
     fn run(
@@ -88,39 +104,77 @@ impl<T: ComputeTrait> render_graph::Node for ComputeNode<T> {
         let encoder = render_context.command_encoder();
 
         // // select the pipeline based on the current state
-        match self.state {
-            ComputeState::Loading => {}
-            ComputeState::Ready => {
+        match self.status {
+            PipelineStatus::Loading | PipelineStatus::Failed(_) => {}
+            PipelineStatus::Ready => {
                 // run multiple passes and dispatch workgroups
                 // seemed like a simple solution, and appears to work
+                let mut dispatched = 0;
                 for pass in passes.passes.iter() {
-                    // get pipeline depending on entry point, need its index
-                    let index = T::entry_points()
-                        .iter()
-                        .position(|&x| x == pass.entry)
-                        .unwrap_or(0);
-                    let Some(pipeline) =
-                        pipeline_cache.get_compute_pipeline(compute_pipelines.pipelines[index])
+                    // get pipeline depending on entry point and shader defs,
+                    // specialized ones were queued and waited on in extract
+                    let Some(pipeline) = compute_pipelines
+                        .get(pass.entry, &pass.shader_defs)
+                        .and_then(|id| pipeline_cache.get_compute_pipeline(id))
                     else {
                         return Ok(());
                     };
 
+                    // the arguments can't be read from a binding the pass also writes, copy
+                    // them into the INDIRECT buffer first, checked in prepare_bind_group
+                    let indirect_buffer = pass.indirect.and_then(|indirect| {
+                        let buffer = prepaired.indirect_buffer.as_ref()?;
+                        match prepaired.bindings.iter().find(|(i, _)| *i == indirect.binding) {
+                            Some((_, OwnedBindingResource::Buffer(args))) => {
+                                encoder.copy_buffer_to_buffer(
+                                    args,
+                                    indirect.offset,
+                                    buffer,
+                                    0,
+                                    buffer.size(),
+                                );
+                                Some(buffer)
+                            }
+                            _ => None,
+                        }
+                    });
+
                     let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
                         label: Some(pass.entry),
                         timestamp_writes: None,
                     });
                     cpass.set_pipeline(pipeline);
-                    cpass.set_bind_group(0, &prepaired.bind_group, &[]);
-                    for workgroup in pass.workgroups.iter() {
-                        cpass.dispatch_workgroups(workgroup.x, workgroup.y, workgroup.z);
+                    // validated against the push constant ranges in extract
+                    if !pass.push_constants.is_empty() {
+                        cpass.set_push_constants(0, &pass.push_constants);
+                    }
+
+                    // each dispatch sees the writes of the one before it, but indirect ones all
+                    // use the arguments as they were when the pass started
+                    for iteration in 0..pass.iterations {
+                        // ping pong pairs swap every iteration, counting on from earlier passes
+                        cpass.set_bind_group(0, prepaired.iteration_bind_group(dispatched), &[]);
+                        dispatched += 1;
+                        if let Some(offset) = pass.iteration_push_constant {
+                            cpass.set_push_constants(offset, bytemuck::bytes_of(&iteration));
+                        }
+                        match indirect_buffer {
+                            Some(buffer) => cpass.dispatch_workgroups_indirect(buffer, 0),
+                            None => {
+                                for workgroup in pass.workgroups.iter() {
+                                    cpass.dispatch_workgroups(workgroup.x, workgroup.y, workgroup.z);
+                                }
+                            }
+                        }
                     }
                 }
 
                 // copy gpu buffer to staging buffer on cpu for storage
-                for (index, staging_buff) in prepaired.staging_buffers.storage.iter() {
-                    // find resource on gpu
+                for (index, staging_buff) in prepaired.staging_buffers.iter() {
+                    // find resource on gpu, the one holding the final state for ping pong pairs
+                    let source = prepaired.staging_source(*index);
                     if let Some((_i, OwnedBindingResource::Buffer(gpu_buffer))) =
-                        prepaired.bindings.iter().find(|(i, _)| i == index)
+                        prepaired.bindings.iter().find(|(i, _)| *i == source)
                     {
                         encoder.copy_buffer_to_buffer(
                             &gpu_buffer,
@@ -134,27 +188,46 @@ impl<T: ComputeTrait> render_graph::Node for ComputeNode<T> {
                     }
                 }
 
-                // copy gpu texture to staging buffer on cpu
-                for (index, (handle, dim)) in passes.images.iter().enumerate() {
+                // copy gpu texture to staging buffer on cpu, one copy per mip level with every
+                // layer or slice, only the region if one was given
+                for (index, image) in passes.images.iter().enumerate() {
                     let buffer = &prepaired.staging_image_buffers[index];
-                    let gpu_image = gpu_images.get(handle).unwrap();
-                    encoder.copy_texture_to_buffer(
-                        gpu_image.texture.as_image_copy(),
-                        ImageCopyBuffer {
-                            buffer: &buffer,
-                            layout: ImageDataLayout {
-                                bytes_per_row: Some(dim.padded_bytes_per_row as u32),
-                                rows_per_image: None,
-                                ..Default::default()
+                    // checked in prepare_bind_group
+                    let Some(gpu_image) = gpu_images.get(&image.handle) else {
+                        continue;
+                    };
+                    for copy in image.copies.iter() {
+                        let origin = copy
+                            .region
+                            .map_or(Origin3d::ZERO, |region| Origin3d {
+                                x: region.min.x,
+                                y: region.min.y,
+                                z: 0,
+                            });
+                        encoder.copy_texture_to_buffer(
+                            ImageCopyTexture {
+                                texture: &gpu_image.texture,
+                                mip_level: copy.mip_level,
+                                origin,
+                                aspect: TextureAspect::All,
                             },
-                        },
-                        Extent3d {
-                            width: dim.width as u32,
-                            height: dim.height as u32,
-                            depth_or_array_layers: 1,
-                        },
-                    );
+                            ImageCopyBuffer {
+                                buffer: &buffer,
+                                layout: ImageDataLayout {
+                                    offset: copy.offset as u64,
+                                    bytes_per_row: Some(copy.dim.padded_bytes_per_row as u32),
+                                    rows_per_image: Some(copy.dim.rows_per_image as u32),
+                                },
+                            },
+                            Extent3d {
+                                width: copy.dim.width as u32,
+                                height: copy.dim.height as u32,
+                                depth_or_array_layers: copy.dim.depth_or_array_layers as u32,
+                            },
+                        );
+                    }
                 }
+                passes.copied.store(true, Ordering::Release);
             }
         }
 
@@ -1,31 +1,432 @@
-use std::{borrow::Cow, marker::PhantomData};
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
 
-use bevy::{prelude::*, render::{render_resource::{BindGroup, BindGroupLayout, Buffer, CachedComputePipelineId, ComputePipelineDescriptor, OwnedBindingResource, PipelineCache, ShaderRef, StageBuffers}, renderer::RenderDevice}};
+use bevy::{ecs::component::Tick, math::URect, prelude::*, render::{render_resource::{BindGroup, BindGroupLayout, Buffer, BufferAddress, BufferDescriptor, BufferUsages, CachedComputePipelineId, ComputePipelineDescriptor, OwnedBindingResource, PipelineCache, ShaderDefVal, ShaderRef, TextureFormat, TextureId}, renderer::RenderDevice}, utils::HashMap};
 
-use crate::{ComputeTrait, Pass};
+use crate::{
+    channel::{ComputeChannelPolicy, ComputeErrorSender},
+    ping_pong_partner, ComputeError, ComputeEvent, ComputeRequestId, ComputeTrait, Pass, PassMerge,
+    Readback,
+};
 
 #[derive(Resource)]
 pub struct PreparedCompute<T: ComputeTrait> {
     pub bindings: Vec<(u32, OwnedBindingResource)>,
     pub bind_group: BindGroup,
-    pub staging_buffers: StageBuffers,
+    /// bind group with the resources of each ping pong pair swapped, used on odd iterations
+    pub swapped_bind_group: Option<BindGroup>,
+    /// the dispatch runs an odd number of iterations, the final state ends up in the
+    /// second binding of each ping pong pair
+    pub ping_pong_odd: bool,
+    /// gpu textures of the staged images when the bind group was made
+    pub image_textures: Vec<TextureId>,
+    /// buffers made with `COPY_SRC` for [`crate::ComputeShader::staged_field`], by binding index
+    pub field_buffers: Vec<(u32, Buffer)>,
+    /// arguments for indirect passes are copied here, see [`crate::Pass::indirect`]
+    pub indirect_buffer: Option<Buffer>,
+    /// staging buffers for storage bindings, by binding index
+    pub staging_buffers: Vec<(u32, Buffer)>,
     pub staging_image_buffers: Vec<Buffer>,
     pub _marker: PhantomData<T>,
 }
 
+impl<T: ComputeTrait> PreparedCompute<T> {
+    /// Bind group for the nth dispatch iteration of this frame, counted across every pass
+    pub fn iteration_bind_group(&self, iteration: u32) -> &BindGroup {
+        match &self.swapped_bind_group {
+            Some(swapped) if iteration % 2 == 1 => swapped,
+            _ => &self.bind_group,
+        }
+    }
+
+    /// Binding whose resource is copied to the staging buffer for index,
+    /// after an odd number of iterations the pairs hold each other's state
+    pub fn staging_source(&self, index: u32) -> u32 {
+        match ping_pong_partner::<T>(index) {
+            Some(partner) if self.ping_pong_odd => partner,
+            _ => index,
+        }
+    }
+
+    /// Swap the resources of each ping pong pair, so the first binding holds the latest state
+    pub(crate) fn swap_ping_pong(&mut self) {
+        for &(a, b) in T::ping_pong_bindings() {
+            let position = |index| self.bindings.iter().position(|(i, _)| *i == index);
+            let (Some(pa), Some(pb)) = (position(a), position(b)) else {
+                continue;
+            };
+            self.bindings.swap(pa, pb);
+            self.bindings[pa].0 = a;
+            self.bindings[pb].0 = b;
+        }
+        if let Some(swapped) = self.swapped_bind_group.as_mut() {
+            std::mem::swap(&mut self.bind_group, swapped);
+        }
+        self.ping_pong_odd = false;
+    }
+}
 
 #[derive(Resource)]
 pub struct RenderComputePasses<T: ComputeTrait> {
     pub passes: Vec<Pass>,
-    pub images: Vec<(Handle<Image>, BufferDimensions)>,
+    /// set by the node once the staging copies are recorded
+    pub copied: AtomicBool,
+    /// events these passes came from
+    pub requests: Vec<ComputeRequestId>,
+    /// the events themselves, held again if the bind group has to wait a frame
+    pub events: Vec<(ComputeEvent<T>, ComputeRequestId)>,
+    /// app world frame the events were extracted on
+    pub submitted_frame: u32,
+    /// bindings of T that changed in the app world since the last dispatch
+    pub changed_bindings: Vec<u32>,
+    /// what to read back, None if every event used no_staging
+    pub readback: Option<Readback>,
+    /// staged images this dispatch reads back
+    pub images: Vec<ImageReadback>,
+    pub _marker: PhantomData<T>,
+}
+
+/// Compute events held in the render world until they can be dispatched, while the pipelines
+/// compile, an image loads or the bind group waits on the gpu
+#[derive(Resource)]
+pub struct PendingComputeEvents<T: ComputeTrait> {
+    pub events: Vec<(ComputeEvent<T>, ComputeRequestId)>,
+    /// bindings of T that changed in the app world since the last dispatch
+    pub changed_bindings: Vec<u32>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for PendingComputeEvents<T> {
+    fn default() -> Self {
+        Self {
+            events: Vec::new(),
+            changed_bindings: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> PendingComputeEvents<T> {
+    /// Hold the events of a dispatch that couldn't be prepared, they go before any sent since
+    pub fn retry(&mut self, passes: &mut RenderComputePasses<T>) {
+        let mut events = std::mem::take(&mut passes.events);
+        events.append(&mut self.events);
+        self.events = events;
+        for binding in passes.changed_bindings.drain(..) {
+            if !self.changed_bindings.contains(&binding) {
+                self.changed_bindings.push(binding);
+            }
+        }
+    }
+}
+
+/// A dispatch whose staging buffers are waiting on map_async
+pub struct PendingReadback<T: ComputeTrait> {
+    pub requests: Vec<ComputeRequestId>,
+    pub submitted_frame: u32,
+    pub passes: Vec<Pass>,
+    pub dont_copy: bool,
+    pub storage: Vec<(u32, Buffer)>,
+    pub images: Vec<(ImageReadback, Buffer)>,
+    /// number of buffers still being mapped
+    pub remaining: Arc<AtomicUsize>,
+    /// set if any map_async callback reported an error
+    pub failed: Arc<AtomicBool>,
+    pub _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> PendingReadback<T> {
+    pub fn is_ready(&self) -> bool {
+        self.remaining.load(Ordering::Acquire) == 0
+    }
+
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Acquire)
+    }
+}
+
+/// Readbacks in flight in the render world, oldest first
+#[derive(Resource)]
+pub struct ComputeReadbacks<T: ComputeTrait> {
+    pub pending: VecDeque<PendingReadback<T>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for ComputeReadbacks<T> {
+    fn default() -> Self {
+        Self {
+            pending: VecDeque::new(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+/// Bindings of T that changed on the cpu this frame, only tracked in persistent mode
+#[derive(Resource)]
+pub struct ChangedBindings<T: ComputeTrait> {
+    pub changed: Vec<u32>,
+    /// T the last time its bindings were compared, readbacks are written into it as well since
+    /// the gpu already has those values
+    pub(crate) snapshot: Option<T>,
+    /// set when a readback was the only change to T, nothing has to be uploaded
+    pub(crate) staged_tick: Option<Tick>,
+}
+
+impl<T: ComputeTrait> Default for ChangedBindings<T> {
+    fn default() -> Self {
+        Self {
+            changed: Vec::new(),
+            snapshot: None,
+            staged_tick: None,
+        }
+    }
+}
+
+/// Settings from [`crate::ComputePlugin`], inserted in both the app and render world
+#[derive(Resource, Clone)]
+pub struct ComputeSettings<T: ComputeTrait> {
+    /// How many dispatches can be waiting on readback before the render world waits on the gpu
+    pub frames_in_flight: usize,
+    /// Mark the resource as changed when staged fields are read back
+    pub change_detection: bool,
+    /// Keep gpu buffers and the bind group between dispatches
+    pub persistent: bool,
+    /// What to do with results when the app world falls behind
+    pub channel_policy: ComputeChannelPolicy,
+    /// How passes from events sent in the same frame are combined
+    pub pass_merge: PassMerge,
     pub _marker: PhantomData<T>,
 }
 
+/// State of the compute pipelines
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum PipelineStatus {
+    /// shader or pipelines still being compiled, dispatches are skipped
+    #[default]
+    Loading,
+    Ready,
+    /// a pipeline failed to compile, with the naga or wgpu message, a fixed shader
+    /// that gets hot reloaded moves it back to Loading
+    Failed(String),
+}
+
+/// State of the compute pipelines for T in the app world, updated at the start of each frame
+#[derive(Resource)]
+pub struct ComputeStatus<T: ComputeTrait> {
+    pub status: PipelineStatus,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for ComputeStatus<T> {
+    fn default() -> Self {
+        Self {
+            status: PipelineStatus::Loading,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> ComputeStatus<T> {
+    pub fn is_ready(&self) -> bool {
+        self.status == PipelineStatus::Ready
+    }
+
+    /// The compile error, if a pipeline failed
+    pub fn error(&self) -> Option<&str> {
+        match &self.status {
+            PipelineStatus::Failed(message) => Some(message),
+            _ => None,
+        }
+    }
+}
+
+/// Pipeline status written by [`ComputeNode`](crate::ComputeNode), shared by the app and render world
+#[derive(Resource)]
+pub struct SharedComputeStatus<T: ComputeTrait> {
+    inner: Arc<Mutex<PipelineStatus>>,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for SharedComputeStatus<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(PipelineStatus::Loading)),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> Clone for SharedComputeStatus<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> SharedComputeStatus<T> {
+    pub fn get(&self) -> PipelineStatus {
+        self.inner.lock().unwrap().clone()
+    }
+
+    /// Returns true if the status changed
+    pub fn set(&self, status: PipelineStatus) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if *inner == status {
+            return false;
+        }
+        *inner = status;
+        true
+    }
+}
+
+/// Staging buffers reused between dispatches, keyed by size and usage.
+/// Shared by the app and render world, storage buffers are returned after they are read in the app world
+#[derive(Resource)]
+pub struct StagingBufferPool<T: ComputeTrait> {
+    inner: Arc<Mutex<StagingPoolInner>>,
+    _marker: PhantomData<T>,
+}
+
+struct StagingPoolInner {
+    free: HashMap<(BufferAddress, BufferUsages), Vec<Buffer>>,
+    /// max free buffers kept for each size
+    max_free: usize,
+    stats: StagingPoolStats,
+}
+
+/// Statistics for a [`StagingBufferPool`]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct StagingPoolStats {
+    /// buffers created since the pool was made
+    pub created: usize,
+    /// times a free buffer was handed out instead of creating one
+    pub reused: usize,
+    /// buffers waiting to be reused
+    pub free: usize,
+    /// size of the buffers waiting to be reused
+    pub free_bytes: BufferAddress,
+}
+
+impl<T: ComputeTrait> Clone for StagingBufferPool<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> StagingBufferPool<T> {
+    pub fn new(max_free: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(StagingPoolInner {
+                free: HashMap::default(),
+                max_free,
+                stats: StagingPoolStats::default(),
+            })),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get an unmapped buffer, creating one if none are free
+    pub fn acquire(
+        &self,
+        render_device: &RenderDevice,
+        size: BufferAddress,
+        usage: BufferUsages,
+    ) -> Buffer {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(buffer) = inner.free.get_mut(&(size, usage)).and_then(|free| free.pop()) {
+            inner.stats.reused += 1;
+            inner.stats.free -= 1;
+            inner.stats.free_bytes -= size;
+            return buffer;
+        }
+
+        inner.stats.created += 1;
+        render_device.create_buffer(&BufferDescriptor {
+            label: Some("compute_staging_buffer"),
+            usage,
+            size,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Return a buffer to the pool, it must be unmapped
+    pub fn release(&self, buffer: Buffer) {
+        let mut inner = self.inner.lock().unwrap();
+        let size = buffer.size();
+        let max_free = inner.max_free;
+        let free = inner.free.entry((size, buffer.usage())).or_default();
+        if free.len() >= max_free {
+            // already have enough of this size, let it drop
+            return;
+        }
+        free.push(buffer);
+        inner.stats.free += 1;
+        inner.stats.free_bytes += size;
+    }
+
+    /// Drop every free buffer
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.free.clear();
+        inner.stats.free = 0;
+        inner.stats.free_bytes = 0;
+    }
+
+    pub fn stats(&self) -> StagingPoolStats {
+        self.inner.lock().unwrap().stats
+    }
+}
+
+/// A staged image to copy back after the passes run
+#[derive(Clone)]
+pub struct ImageReadback {
+    pub handle: Handle<Image>,
+    /// one copy per mip level, packed one after another in the staging buffer
+    pub copies: Vec<MipCopy>,
+}
+
+impl ImageReadback {
+    /// size of the staging buffer needed for every copy
+    pub fn size(&self) -> usize {
+        self.copies
+            .last()
+            .map_or(0, |copy| copy.offset + copy.dim.size())
+    }
+}
+
+/// Copy of one mip level of an image, every array layer or 3d slice is included
+#[derive(Copy, Clone)]
+pub struct MipCopy {
+    pub mip_level: u32,
+    /// part of the mip level to copy, None copies all of it
+    pub region: Option<URect>,
+    /// dimensions of what is copied, the region if there is one
+    pub dim: BufferDimensions,
+    /// offset into the staging buffer
+    pub offset: usize,
+}
+
 // nore a util, but used as a resource
 #[derive(Copy, Clone)]
 pub struct BufferDimensions {
     pub width: usize,
     pub height: usize,    
+    /// depth for 3d textures, number of layers for array textures
+    pub depth_or_array_layers: usize,
+    /// rows in the buffer for each layer or slice, less than height for compressed formats
+    pub rows_per_image: usize,
     pub unpadded_bytes_per_row: usize,
     pub padded_bytes_per_row: usize,
 }
@@ -37,6 +438,9 @@ pub struct ComputePipeline<T: ComputeTrait> {
     // pipelines ordered by entry point
     pub pipelines: Vec<CachedComputePipelineId>,
     pub bind_group_layout: BindGroupLayout,
+    /// pipelines for passes with extra shader defs, by entry point and defs
+    pub specialized: HashMap<(String, Vec<ShaderDefVal>), CachedComputePipelineId>,
+    shader: Option<Handle<Shader>>,
     pub _marker: PhantomData<T>,
 }
 
@@ -48,45 +452,206 @@ impl<T: ComputeTrait> FromWorld for ComputePipeline<T> {
             ShaderRef::Default => None,
             ShaderRef::Handle(handle) => Some(handle),
             ShaderRef::Path(path) => Some(asset_server.load(path)),
-        }
-        .unwrap();
-
+        };
         let bind_group_layout = T::bind_group_layout(&render_device);
+
+        // there is no default compute shader, without one no pipelines are made and passes are skipped
+        let Some(shader) = shader else {
+            world.resource::<ComputeErrorSender<T>>().report(
+                Vec::new(),
+                ComputeError::Pipeline {
+                    entry: String::new(),
+                    message: "ComputeShader::shader returned ShaderRef::Default".to_string(),
+                },
+            );
+            return Self {
+                bind_group_layout,
+                pipelines: Vec::new(),
+                specialized: HashMap::default(),
+                shader: None,
+                _marker: Default::default(),
+            };
+        };
+
         let pipeline_cache = world.resource::<PipelineCache>();
 
-        let mut pipelines = Vec::new();
-        for entry in T::entry_points() {
-            let pipeline = pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
-                label: None,
-                layout: vec![bind_group_layout.clone()], // S::layouts().to_vec(), use case?
-                push_constant_ranges: T::push_constant_ranges().to_vec(),
-                shader_defs: T::shader_defs().to_vec(),
-                entry_point: Cow::Borrowed(entry),
-                shader: shader.clone(), // TODO: how bad is this clone, could I use weak ref?
-            });
-            pipelines.push(pipeline);
-        }
+        let pipelines = T::entry_points()
+            .into_iter()
+            .map(|entry| {
+                queue_pipeline::<T>(pipeline_cache, &bind_group_layout, &shader, entry, &[])
+            })
+            .collect();
 
         Self {
-            bind_group_layout: bind_group_layout,
+            bind_group_layout,
             pipelines,
+            specialized: HashMap::default(),
+            shader: Some(shader),
             _marker: Default::default(),
         }
     }
 }
 
+impl<T: ComputeTrait> ComputePipeline<T> {
+    /// Pipeline for entry compiled with shader_defs on top of [`ComputeShader::shader_defs`],
+    /// queued the first time it's asked for. None if there is no shader
+    ///
+    /// [`ComputeShader::shader_defs`]: crate::ComputeShader::shader_defs
+    pub fn specialize(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        entry: &str,
+        shader_defs: &[ShaderDefVal],
+    ) -> Option<CachedComputePipelineId> {
+        if shader_defs.is_empty() {
+            return self.get(entry, shader_defs);
+        }
+        let shader = self.shader.as_ref()?;
+        let key = (entry.to_string(), shader_defs.to_vec());
+        if let Some(id) = self.specialized.get(&key) {
+            return Some(*id);
+        }
+        let id = queue_pipeline::<T>(
+            pipeline_cache,
+            &self.bind_group_layout,
+            shader,
+            entry,
+            shader_defs,
+        );
+        self.specialized.insert(key, id);
+        Some(id)
+    }
+
+    /// Pipeline already queued for entry and shader_defs
+    pub fn get(&self, entry: &str, shader_defs: &[ShaderDefVal]) -> Option<CachedComputePipelineId> {
+        if shader_defs.is_empty() {
+            let index = T::entry_points().iter().position(|&x| x == entry)?;
+            return self.pipelines.get(index).copied();
+        }
+        self.specialized
+            .get(&(entry.to_string(), shader_defs.to_vec()))
+            .copied()
+    }
+}
+
+fn queue_pipeline<T: ComputeTrait>(
+    pipeline_cache: &PipelineCache,
+    bind_group_layout: &BindGroupLayout,
+    shader: &Handle<Shader>,
+    entry: &str,
+    shader_defs: &[ShaderDefVal],
+) -> CachedComputePipelineId {
+    pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+        label: None,
+        layout: vec![bind_group_layout.clone()], // S::layouts().to_vec(), use case?
+        push_constant_ranges: T::push_constant_ranges().to_vec(),
+        shader_defs: T::shader_defs().iter().chain(shader_defs).cloned().collect(),
+        entry_point: Cow::Owned(entry.to_string()),
+        shader: shader.clone(), // TODO: how bad is this clone, could I use weak ref?
+    })
+}
+
 
 impl BufferDimensions {
     pub fn new(width: usize, height: usize, bytes_per_pixel: usize) -> Self {
-        let unpadded_bytes_per_row = width * bytes_per_pixel;
+        Self::new_3d(width, height, 1, bytes_per_pixel)
+    }
+
+    pub fn new_3d(
+        width: usize,
+        height: usize,
+        depth_or_array_layers: usize,
+        bytes_per_pixel: usize,
+    ) -> Self {
+        Self::with_blocks(width, height, depth_or_array_layers, height, width * bytes_per_pixel)
+    }
+
+    /// Dimensions to copy a texture of this format, compressed formats are copied in whole blocks.
+    /// None if the format can't be copied with every aspect at once, like combined depth stencil
+    pub fn for_format(
+        width: usize,
+        height: usize,
+        depth_or_array_layers: usize,
+        format: TextureFormat,
+    ) -> Option<Self> {
+        let block_size = format.block_copy_size(None)? as usize;
+        let (block_width, block_height) = format.block_dimensions();
+        Some(Self::with_blocks(
+            width,
+            height,
+            depth_or_array_layers,
+            height.div_ceil(block_height as usize),
+            width.div_ceil(block_width as usize) * block_size,
+        ))
+    }
+
+    fn with_blocks(
+        width: usize,
+        height: usize,
+        depth_or_array_layers: usize,
+        rows_per_image: usize,
+        unpadded_bytes_per_row: usize,
+    ) -> Self {
         let align = 256usize; // wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as usize;
         let padded_bytes_per_row_padding = (align - unpadded_bytes_per_row % align) % align;
         let padded_bytes_per_row = unpadded_bytes_per_row + padded_bytes_per_row_padding;
         Self {
             width,
             height,
+            depth_or_array_layers,
+            rows_per_image,
             unpadded_bytes_per_row,
             padded_bytes_per_row,
         }
     }
+
+    /// size of the padded buffer
+    pub fn size(&self) -> usize {
+        self.padded_bytes_per_row * self.rows()
+    }
+
+    /// rows across every slice or layer
+    pub fn rows(&self) -> usize {
+        self.rows_per_image * self.depth_or_array_layers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pads_rows_to_the_copy_alignment() {
+        let dim = BufferDimensions::for_format(100, 10, 1, TextureFormat::Rgba8Unorm).unwrap();
+        assert_eq!(dim.unpadded_bytes_per_row, 400);
+        assert_eq!(dim.padded_bytes_per_row, 512);
+        assert_eq!(dim.rows_per_image, 10);
+        assert_eq!(dim.size(), 5120);
+
+        let dim = BufferDimensions::for_format(64, 64, 1, TextureFormat::Rgba8Unorm).unwrap();
+        assert_eq!(dim.padded_bytes_per_row, 256);
+    }
+
+    #[test]
+    fn counts_every_layer() {
+        let dim = BufferDimensions::for_format(4, 4, 6, TextureFormat::R32Float).unwrap();
+        assert_eq!(dim.rows(), 24);
+        assert_eq!(dim.size(), 24 * 256);
+    }
+
+    #[test]
+    fn compressed_formats_copy_whole_blocks() {
+        // 4x4 blocks of 8 bytes, a partial block still takes a whole one
+        let dim = BufferDimensions::for_format(10, 10, 1, TextureFormat::Bc1RgbaUnorm).unwrap();
+        assert_eq!(dim.unpadded_bytes_per_row, 3 * 8);
+        assert_eq!(dim.rows_per_image, 3);
+        assert_eq!(dim.padded_bytes_per_row, 256);
+    }
+
+    #[test]
+    fn combined_depth_stencil_cant_be_copied() {
+        assert!(
+            BufferDimensions::for_format(4, 4, 1, TextureFormat::Depth24PlusStencil8).is_none()
+        );
+    }
 }
@@ -1,6 +1,9 @@
 use bevy::{
     prelude::*,
-    render::{extract_resource::ExtractResource, render_graph::RenderGraph, render_resource::{AsBindGroup, PushConstantRange, ShaderDefVal, ShaderRef}},
+    render::{extract_resource::ExtractResource, render_graph::RenderGraph, render_resource::{
+        encase::{self, internal::{ReadFrom, WriteInto}},
+        AsBindGroup, PushConstantRange, ShaderDefVal, ShaderRef, ShaderType,
+    }},
 };
 
 // Define a new trait with all the combined requirements
@@ -38,10 +41,68 @@ pub trait ComputeShader: Send + Sync + 'static {
         &[]
     }
 
+    /// Push constant ranges for the pipelines, filled per pass with
+    /// [`Pass::with_push_constants`](crate::Pass::with_push_constants).
+    /// Needs the `PUSH_CONSTANTS` wgpu feature
     fn push_constant_ranges<'a>() -> &'a [PushConstantRange] {
         &[]
     }
 
+    /// Bindings whose contents live on the gpu when [`ComputePlugin::with_persistent`] is used.
+    /// They are uploaded once, after that changes to T in the app world don't overwrite them
+    ///
+    /// [`ComputePlugin::with_persistent`]: crate::ComputePlugin::with_persistent
+    fn persistent_bindings<'a>() -> &'a [u32] {
+        &[]
+    }
+
+    /// Used by [`ComputePlugin::with_persistent`] to only upload the bindings that changed on the
+    /// cpu, `previous` is T the last time it was compared. By default every binding counts as
+    /// changed whenever T does, see [`binding_fields!`](crate::binding_fields) to compare fields
+    ///
+    /// [`ComputePlugin::with_persistent`]: crate::ComputePlugin::with_persistent
+    fn binding_changed(&self, _previous: &Self, _binding: u32) -> bool {
+        true
+    }
+
+    /// Bindings copied back after the passes run, on top of the ones marked `staging`. They are
+    /// decoded with encase, so any `ShaderType` works, unlike `staging` which needs `T: Pod`.
+    /// Declare the field as plain `#[storage(n)]`, its buffer is made with `COPY_SRC` so it can
+    /// be copied. Uniforms are read only in wgsl, so results a shader writes need a storage binding
+    /// ```ignore
+    /// fn staged_field(&mut self, binding: u32) -> Option<&mut dyn StagedField> {
+    ///     match binding {
+    ///         2 => Some(&mut self.result),
+    ///         _ => None,
+    ///     }
+    /// }
+    /// ```
+    fn staged_field(&mut self, _binding: u32) -> Option<&mut dyn StagedField> {
+        None
+    }
+
+    /// `#[buffer]` bindings copied back after the passes run. There is no field to write them
+    /// into, so the bytes are sent with [`ComputeComplete`](crate::ComputeComplete) instead,
+    /// the buffer has to be created with `COPY_SRC`
+    fn staged_buffers<'a>() -> &'a [u32] {
+        &[]
+    }
+
+    /// Shader defs worked out from the fields of self, added to every pass when it is extracted.
+    /// Passes using them get a specialized pipeline, so toggling a field recompiles the shader
+    /// the first time a combination is seen, see [`shader_def_fields!`](crate::shader_def_fields)
+    fn dynamic_shader_defs(&self) -> Vec<ShaderDefVal> {
+        Vec::new()
+    }
+
+    /// Pairs of storage bindings swapped between dispatch iterations, for simulations that read
+    /// the last state from the first binding and write the next one to the second.
+    /// Both must be buffers of the same size. Once the passes have run the first binding's field
+    /// is staged back with the final state, the second's with the state before it
+    fn ping_pong_bindings<'a>() -> &'a [(u32, u32)] {
+        &[]
+    }
+
     fn set_nodes(render_graph: &mut RenderGraph);
 
     /// By default, the shader entry point is `main`.
@@ -51,3 +112,339 @@ pub trait ComputeShader: Send + Sync + 'static {
         vec!["main"]
     }
 }
+
+/// A field copied back from a storage buffer, see [`ComputeShader::staged_field`]
+pub trait StagedField {
+    /// Decode the bytes copied back into self
+    fn read(&mut self, bytes: &[u8]);
+
+    /// Encode self, used to fill the `COPY_SRC` buffer the field is copied back from
+    fn write(&self) -> Vec<u8>;
+}
+
+impl<S: ShaderType + ReadFrom + WriteInto> StagedField for S {
+    fn read(&mut self, bytes: &[u8]) {
+        read_storage(bytes, self);
+    }
+
+    fn write(&self) -> Vec<u8> {
+        write_storage(self)
+    }
+}
+
+/// Decode bytes copied back from a storage buffer into value, using the storage layout rules
+pub fn read_storage<S: ShaderType + ReadFrom>(bytes: &[u8], value: &mut S) {
+    if let Err(err) = encase::StorageBuffer::new(bytes).read(value) {
+        error!("failed to read staged storage buffer: {}", err);
+    }
+}
+
+/// Encode value with the storage layout rules, the inverse of [`read_storage`]
+pub fn write_storage<S: ShaderType + WriteInto>(value: &S) -> Vec<u8> {
+    let mut buffer = encase::StorageBuffer::new(Vec::new());
+    if let Err(err) = buffer.write(value) {
+        error!("failed to write staged storage buffer: {}", err);
+    }
+    buffer.into_inner()
+}
+
+/// Implements [`ComputeShader::binding_changed`] by comparing the field behind each binding,
+/// fields need `PartialEq`, bindings that aren't listed always count as changed
+/// ```ignore
+/// impl ComputeShader for Simulation {
+///     fn shader() -> ShaderRef {
+///         "simulation.wgsl".into()
+///     }
+///
+///     fn set_nodes(render_graph: &mut RenderGraph) { ... }
+///
+///     binding_fields! {
+///         0 => settings,
+///         1 => particles,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! binding_fields {
+    ($($binding:literal => $field:ident),* $(,)?) => {
+        fn binding_changed(&self, previous: &Self, binding: u32) -> bool {
+            match binding {
+                $($binding => self.$field != previous.$field,)*
+                _ => true,
+            }
+        }
+    };
+}
+
+/// Implements [`ComputeShader::staged_field`], each binding is decoded into its field with encase.
+/// Works for any `ShaderType`, so structs with padding, `Color`, `Vec3` and runtime sized arrays
+/// of structs can be read back, unlike `staging` which needs `T: Pod`. Declare the fields as
+/// plain `#[storage(n)]` without `staging`
+/// ```ignore
+/// impl ComputeShader for Particles {
+///     fn shader() -> ShaderRef {
+///         "particles.wgsl".into()
+///     }
+///
+///     fn set_nodes(render_graph: &mut RenderGraph) { ... }
+///
+///     read_staged_fields! {
+///         1 => particles,
+///         2 => colors,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! read_staged_fields {
+    ($($binding:literal => $field:ident),* $(,)?) => {
+        fn staged_field(&mut self, binding: u32) -> Option<&mut dyn $crate::StagedField> {
+            match binding {
+                $($binding => Some(&mut self.$field),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+/// Field types that can be turned into a shader def by [`shader_def_fields!`](crate::shader_def_fields)
+pub trait ShaderDefField {
+    fn shader_def(&self, name: &str) -> ShaderDefVal;
+}
+
+impl ShaderDefField for bool {
+    fn shader_def(&self, name: &str) -> ShaderDefVal {
+        ShaderDefVal::Bool(name.to_string(), *self)
+    }
+}
+
+impl ShaderDefField for i32 {
+    fn shader_def(&self, name: &str) -> ShaderDefVal {
+        ShaderDefVal::Int(name.to_string(), *self)
+    }
+}
+
+impl ShaderDefField for u32 {
+    fn shader_def(&self, name: &str) -> ShaderDefVal {
+        ShaderDefVal::UInt(name.to_string(), *self)
+    }
+}
+
+/// Implements [`ComputeShader::dynamic_shader_defs`] from `bool`, `i32` and `u32` fields,
+/// so changing one in the inspector specializes the shader
+/// ```ignore
+/// impl ComputeShader for Brush {
+///     fn shader() -> ShaderRef {
+///         "brush.wgsl".into()
+///     }
+///
+///     fn set_nodes(render_graph: &mut RenderGraph) { ... }
+///
+///     shader_def_fields! {
+///         "SMOOTH" => smooth,
+///         "BRUSH_MODE" => mode,
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! shader_def_fields {
+    ($($name:literal => $field:ident),* $(,)?) => {
+        fn dynamic_shader_defs(&self) -> Vec<bevy::render::render_resource::ShaderDefVal> {
+            vec![$($crate::ShaderDefField::shader_def(&self.$field, $name)),*]
+        }
+    };
+}
+
+/// The binding paired with index in [`ComputeShader::ping_pong_bindings`]
+pub fn ping_pong_partner<T: ComputeShader>(index: u32) -> Option<u32> {
+    T::ping_pong_bindings().iter().find_map(|&(a, b)| {
+        if a == index {
+            Some(b)
+        } else if b == index {
+            Some(a)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fields {
+        settings: f32,
+        particles: Vec<u32>,
+    }
+
+    impl ComputeShader for Fields {
+        fn shader() -> ShaderRef {
+            ShaderRef::Default
+        }
+
+        fn set_nodes(_render_graph: &mut RenderGraph) {}
+
+        binding_fields! {
+            0 => settings,
+            1 => particles,
+        }
+    }
+
+    #[test]
+    fn binding_fields_compare_each_field() {
+        let previous = Fields {
+            settings: 1.0,
+            particles: vec![1, 2, 3],
+        };
+        let mut current = Fields {
+            settings: 1.0,
+            particles: vec![1, 2, 3],
+        };
+        assert!(!current.binding_changed(&previous, 0));
+        assert!(!current.binding_changed(&previous, 1));
+
+        current.particles.push(4);
+        assert!(!current.binding_changed(&previous, 0));
+        assert!(current.binding_changed(&previous, 1));
+
+        // unlisted bindings can't be compared
+        assert!(current.binding_changed(&previous, 2));
+    }
+    #[derive(ShaderType, Clone, Debug, PartialEq)]
+    struct Particle {
+        position: Vec3,
+        velocity: Vec2,
+    }
+
+    #[test]
+    fn staged_fields_round_trip_padded_structs() {
+        let particles = vec![
+            Particle {
+                position: Vec3::new(1.0, 2.0, 3.0),
+                velocity: Vec2::new(4.0, 5.0),
+            },
+            Particle {
+                position: Vec3::splat(-1.0),
+                velocity: Vec2::ZERO,
+            },
+        ];
+        let bytes = StagedField::write(&particles);
+        // vec3 is 16 byte aligned, so each element is padded to 32 bytes
+        assert_eq!(bytes.len(), 64);
+
+        let mut read = Vec::new();
+        StagedField::read(&mut read, &bytes);
+        assert_eq!(read, particles);
+    }
+
+    #[test]
+    fn staged_fields_round_trip_colors() {
+        let colors = vec![
+            Color::rgba_linear(1.0, 0.0, 0.0, 1.0),
+            Color::rgba_linear(0.0, 0.5, 1.0, 0.5),
+        ];
+        let bytes = StagedField::write(&colors);
+        assert_eq!(bytes.len(), 32);
+
+        let mut read = Vec::new();
+        StagedField::read(&mut read, &bytes);
+        assert_eq!(read, colors);
+    }
+    struct Staged {
+        particles: Vec<Particle>,
+        count: u32,
+    }
+
+    impl ComputeShader for Staged {
+        fn shader() -> ShaderRef {
+            ShaderRef::Default
+        }
+
+        fn set_nodes(_render_graph: &mut RenderGraph) {}
+
+        read_staged_fields! {
+            1 => particles,
+            2 => count,
+        }
+    }
+
+    #[test]
+    fn read_staged_fields_decodes_each_binding() {
+        let mut staged = Staged {
+            particles: Vec::new(),
+            count: 0,
+        };
+        let particles = vec![Particle {
+            position: Vec3::X,
+            velocity: Vec2::Y,
+        }];
+
+        staged
+            .staged_field(1)
+            .unwrap()
+            .read(&write_storage(&particles));
+        staged.staged_field(2).unwrap().read(&write_storage(&7u32));
+        assert_eq!(staged.particles, particles);
+        assert_eq!(staged.count, 7);
+
+        assert!(staged.staged_field(0).is_none());
+    }
+
+    struct Brush {
+        smooth: bool,
+        mode: u32,
+        offset: i32,
+    }
+
+    impl ComputeShader for Brush {
+        fn shader() -> ShaderRef {
+            ShaderRef::Default
+        }
+
+        fn set_nodes(_render_graph: &mut RenderGraph) {}
+
+        shader_def_fields! {
+            "SMOOTH" => smooth,
+            "BRUSH_MODE" => mode,
+            "OFFSET" => offset,
+        }
+    }
+
+    #[test]
+    fn shader_def_fields_make_a_def_per_field() {
+        let brush = Brush {
+            smooth: true,
+            mode: 2,
+            offset: -1,
+        };
+        assert_eq!(
+            brush.dynamic_shader_defs(),
+            vec![
+                ShaderDefVal::Bool("SMOOTH".to_string(), true),
+                ShaderDefVal::UInt("BRUSH_MODE".to_string(), 2),
+                ShaderDefVal::Int("OFFSET".to_string(), -1),
+            ]
+        );
+    }
+
+    struct Simulation;
+
+    impl ComputeShader for Simulation {
+        fn shader() -> ShaderRef {
+            ShaderRef::Default
+        }
+
+        fn set_nodes(_render_graph: &mut RenderGraph) {}
+
+        fn ping_pong_bindings<'a>() -> &'a [(u32, u32)] {
+            &[(1, 2), (4, 3)]
+        }
+    }
+
+    #[test]
+    fn ping_pong_partners_work_both_ways() {
+        assert_eq!(ping_pong_partner::<Simulation>(1), Some(2));
+        assert_eq!(ping_pong_partner::<Simulation>(2), Some(1));
+        assert_eq!(ping_pong_partner::<Simulation>(3), Some(4));
+        assert_eq!(ping_pong_partner::<Simulation>(0), None);
+    }
+}
@@ -0,0 +1,198 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::render_resource::{ShaderRef, Source},
+    utils::HashMap,
+};
+
+use crate::ComputeTrait;
+
+/// `@workgroup_size` of each compute entry point in T's shader, reflected when the shader loads
+/// or is modified, used to size passes made with [`Pass::for_invocations`](crate::Pass::for_invocations)
+#[derive(Resource)]
+pub struct WorkgroupSizes<T: ComputeTrait> {
+    sizes: HashMap<String, UVec3>,
+    reflected: bool,
+    _marker: PhantomData<T>,
+}
+
+impl<T: ComputeTrait> Default for WorkgroupSizes<T> {
+    fn default() -> Self {
+        Self {
+            sizes: HashMap::default(),
+            reflected: false,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: ComputeTrait> WorkgroupSizes<T> {
+    /// The shader has loaded and been reflected, entry points missing after this never show up
+    pub fn is_reflected(&self) -> bool {
+        self.reflected
+    }
+
+    pub fn get(&self, entry: &str) -> Option<UVec3> {
+        self.sizes.get(entry).copied()
+    }
+
+    /// Workgroups needed to cover invocations, rounded up, None if the entry point isn't known yet
+    pub fn workgroups_for(&self, entry: &str, invocations: UVec3) -> Option<UVec3> {
+        let size = self.get(entry)?.max(UVec3::ONE);
+        Some((invocations + size - UVec3::ONE) / size)
+    }
+}
+
+/// Reflect `@workgroup_size` for every compute entry point, naga can't parse shaders that use
+/// imports or shader defs, those fall back to reading the attributes from the source
+pub fn reflect_workgroup_sizes(source: &str) -> HashMap<String, UVec3> {
+    match naga::front::wgsl::parse_str(source) {
+        Ok(module) => module
+            .entry_points
+            .iter()
+            .filter(|entry| entry.stage == naga::ShaderStage::Compute)
+            .map(|entry| (entry.name.clone(), UVec3::from_array(entry.workgroup_size)))
+            .collect(),
+        Err(_) => scan_workgroup_sizes(source),
+    }
+}
+
+// finds `@workgroup_size(x, y, z)` and the name of the fn that follows it, sizes that
+// use constants or overrides are skipped
+fn scan_workgroup_sizes(source: &str) -> HashMap<String, UVec3> {
+    let mut sizes = HashMap::default();
+    let mut rest = source;
+    while let Some(start) = rest.find("@workgroup_size(") {
+        rest = &rest[start + "@workgroup_size(".len()..];
+        let Some(end) = rest.find(')') else {
+            break;
+        };
+        let args = rest[..end]
+            .split(',')
+            .map(|arg| arg.trim())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.trim_end_matches('u').parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>();
+        let Some(name) = rest.find("fn ").map(|fn_start| {
+            rest[fn_start + 3..]
+                .trim_start()
+                .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .next()
+                .unwrap_or_default()
+        }) else {
+            break;
+        };
+        if let Some(args) = args.filter(|args| (1..=3).contains(&args.len())) {
+            let size = UVec3::new(
+                args[0],
+                args.get(1).copied().unwrap_or(1),
+                args.get(2).copied().unwrap_or(1),
+            );
+            sizes.insert(name.to_string(), size);
+        }
+    }
+    sizes
+}
+
+/// System to reflect the workgroup sizes of T's shader when it's loaded or modified
+pub fn update_workgroup_sizes<T: ComputeTrait>(
+    mut events: EventReader<AssetEvent<Shader>>,
+    mut shader_handle: Local<Option<Handle<Shader>>>,
+    mut workgroup_sizes: ResMut<WorkgroupSizes<T>>,
+    shaders: Res<Assets<Shader>>,
+    asset_server: Res<AssetServer>,
+) {
+    let handle = match shader_handle.as_ref() {
+        Some(handle) => handle,
+        None => {
+            let handle = match T::shader() {
+                ShaderRef::Handle(handle) => handle,
+                ShaderRef::Path(path) => asset_server.load(path),
+                // reported as a pipeline error when the pipelines are created
+                ShaderRef::Default => return,
+            };
+            shader_handle.insert(handle)
+        }
+    };
+
+    let updated = events.read().any(|event| match event {
+        AssetEvent::Added { id } | AssetEvent::Modified { id } => *id == handle.id(),
+        _ => false,
+    });
+    // the shader may have loaded before we started listening
+    if !updated && workgroup_sizes.reflected {
+        return;
+    }
+    let Some(shader) = shaders.get(handle) else {
+        return;
+    };
+    workgroup_sizes.reflected = true;
+    match &shader.source {
+        Source::Wgsl(source) => workgroup_sizes.sizes = reflect_workgroup_sizes(source),
+        _ => warn!("workgroup sizes can only be reflected from wgsl shaders"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::Simple;
+
+    #[test]
+    fn scans_sizes_naga_cant_parse() {
+        let source = r#"
+            #import bevy_sly_compute::indirect::DispatchIndirectArgs
+
+            @compute @workgroup_size(8, 8, 1)
+            fn main(@builtin(global_invocation_id) id: vec3<u32>) {}
+
+            @compute @workgroup_size(64)
+            fn  line_pass() {}
+
+            @compute
+            @workgroup_size(16u, 4u)
+            fn tile_pass() {}
+
+            @compute @workgroup_size(SIZE, 1, 1)
+            fn constant_pass() {}
+        "#;
+        let sizes = reflect_workgroup_sizes(source);
+        assert_eq!(sizes.get("main"), Some(&UVec3::new(8, 8, 1)));
+        assert_eq!(sizes.get("line_pass"), Some(&UVec3::new(64, 1, 1)));
+        assert_eq!(sizes.get("tile_pass"), Some(&UVec3::new(16, 4, 1)));
+        assert_eq!(sizes.get("constant_pass"), None);
+        assert_eq!(sizes, scan_workgroup_sizes(source));
+    }
+
+    #[test]
+    fn reflects_with_naga() {
+        let source = r#"
+            @group(0) @binding(0) var<storage, read_write> values: array<f32>;
+
+            @compute @workgroup_size(32, 2)
+            fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+                values[id.x] = 1.0;
+            }
+        "#;
+        let sizes = reflect_workgroup_sizes(source);
+        assert_eq!(sizes.len(), 1);
+        assert_eq!(sizes.get("main"), Some(&UVec3::new(32, 2, 1)));
+    }
+
+    #[test]
+    fn workgroups_round_up() {
+        let mut workgroup_sizes = WorkgroupSizes::<Simple>::default();
+        assert_eq!(workgroup_sizes.workgroups_for("main", UVec3::ONE), None);
+
+        workgroup_sizes.sizes.insert("main".to_string(), UVec3::new(8, 8, 1));
+        assert_eq!(
+            workgroup_sizes.workgroups_for("main", UVec3::new(1024, 1000, 3)),
+            Some(UVec3::new(128, 125, 3))
+        );
+        assert_eq!(
+            workgroup_sizes.workgroups_for("main", UVec3::new(1025, 1, 1)),
+            Some(UVec3::new(129, 1, 1))
+        );
+    }
+}